@@ -0,0 +1,163 @@
+use std::hint::black_box;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hithit_bot::booking::{Booked, BookingStore, MessageKey};
+use lru_cache::LruCache;
+use teloxide::types::{ChatId, MessageId, User, UserId};
+
+const THREADS: usize = 8;
+const CHATS: usize = 1024;
+const COMMANDS_PER_THREAD: usize = 10_000;
+const CAPACITY: usize = 8192;
+
+fn booked(chat: usize, message: usize, sender: usize) -> Booked {
+    Booked {
+        message: MessageKey {
+            chat_id: ChatId(i64::try_from(chat).unwrap()),
+            message_id: MessageId(i32::try_from(message).unwrap()),
+        },
+        sender: UserId(sender as u64),
+    }
+}
+
+// The cache the store replaced: both directions keyed by the whole message, sender included, in
+// two LRU maps behind one lock.
+#[derive(Clone, Eq, PartialEq, Hash)]
+struct MessageMeta {
+    chat_id: ChatId,
+    message_id: MessageId,
+    sender: User,
+}
+
+struct Legacy {
+    forward: LruCache<MessageMeta, (MessageMeta, Instant)>,
+    reverse: LruCache<MessageMeta, (MessageMeta, Instant)>,
+}
+
+impl Legacy {
+    fn new(capacity: usize) -> Mutex<Self> {
+        Mutex::new(Self {
+            forward: LruCache::new(capacity),
+            reverse: LruCache::new(capacity),
+        })
+    }
+}
+
+fn meta(booked: Booked) -> MessageMeta {
+    MessageMeta {
+        chat_id: booked.message.chat_id,
+        message_id: booked.message.message_id,
+        sender: User {
+            id: booked.sender,
+            is_bot: booked.sender.0 == 0,
+            first_name: format!("user {}", booked.sender),
+            last_name: None,
+            username: Some(format!("user_{}", booked.sender)),
+            language_code: Some(String::from("en")),
+            is_premium: false,
+            added_to_attachment_menu: false,
+        },
+    }
+}
+
+trait Store: Sync {
+    fn book(&self, command: Booked, reply: Booked);
+    fn lookup_both_ways(&self, command: Booked, reply: Booked);
+}
+
+impl Store for BookingStore {
+    fn book(&self, command: Booked, reply: Booked) {
+        Self::book(self, command, reply);
+    }
+    fn lookup_both_ways(&self, command: Booked, reply: Booked) {
+        black_box(self.reverse_lookup(reply.message));
+        black_box(self.forward_lookup(command.message));
+    }
+}
+
+impl Store for Mutex<Legacy> {
+    fn book(&self, command: Booked, reply: Booked) {
+        let (command, reply) = (meta(command), meta(reply));
+        let mut cache = self.lock().unwrap();
+        if let Some((previous, _)) = cache.forward.remove(&command) {
+            cache.reverse.remove(&previous);
+        }
+        let now = Instant::now();
+        cache.forward.insert(command.clone(), (reply.clone(), now));
+        cache.reverse.insert(reply, (command, now));
+    }
+    fn lookup_both_ways(&self, command: Booked, reply: Booked) {
+        // The old handlers built the keys from the incoming messages, cloning their senders.
+        let (command, reply) = (meta(command), meta(reply));
+        let mut cache = self.lock().unwrap();
+        black_box(cache.reverse.get_mut(&reply).map(|(meta, _)| meta.clone()));
+        black_box(
+            cache
+                .forward
+                .get_mut(&command)
+                .map(|(meta, _)| meta.clone()),
+        );
+    }
+}
+
+// Every thread handles its own chats, booking a reply for each command and looking it up both
+// ways, as the bot does when a command is sent, edited or hit back.
+fn handle_commands(store: &impl Store) {
+    thread::scope(|scope| {
+        for thread in 0..THREADS {
+            scope.spawn(move || {
+                for i in 0..COMMANDS_PER_THREAD {
+                    let chat = (i * THREADS + thread) % CHATS;
+                    let command = booked(chat, i * 2, thread + 1);
+                    let reply = booked(chat, i * 2 + 1, 0);
+                    store.book(command, reply);
+                    store.lookup_both_ways(command, reply);
+                }
+            });
+        }
+    });
+}
+
+fn time(iters: u64, store: impl Fn() -> Box<dyn Fn()>) -> Duration {
+    (0..iters)
+        .map(|_| {
+            let run = store();
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .sum()
+}
+
+// `legacy` is the cache as it was before the store; one shard shows what sharding alone buys.
+fn concurrent_chats(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_chats");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((THREADS * COMMANDS_PER_THREAD) as u64));
+    group.bench_function("legacy", |b| {
+        b.iter_custom(|iters| {
+            time(iters, || {
+                let store = Legacy::new(CAPACITY);
+                Box::new(move || handle_commands(&store))
+            })
+        });
+    });
+    for shards in [1, 64] {
+        group.bench_with_input(BenchmarkId::new("shards", shards), &shards, |b, &shards| {
+            b.iter_custom(|iters| {
+                time(iters, || {
+                    let store =
+                        BookingStore::with_shards(CAPACITY, Duration::from_secs(60 * 60), shards);
+                    Box::new(move || handle_commands(&store))
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_chats);
+criterion_main!(benches);
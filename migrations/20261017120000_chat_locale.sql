@@ -0,0 +1,5 @@
+CREATE TABLE chat_locale
+(
+    id     BIGINT PRIMARY KEY,
+    locale TEXT NOT NULL
+)
@@ -0,0 +1,5 @@
+CREATE TABLE chat_timezone
+(
+    id         BIGINT PRIMARY KEY,
+    utc_offset INTEGER NOT NULL
+)
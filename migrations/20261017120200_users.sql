@@ -0,0 +1,11 @@
+CREATE TABLE users
+(
+    id         BIGINT PRIMARY KEY,
+    is_bot     BOOLEAN     NOT NULL,
+    first_name TEXT        NOT NULL,
+    last_name  TEXT,
+    username   TEXT,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX users_username ON users (lower(username));
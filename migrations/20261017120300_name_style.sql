@@ -0,0 +1,12 @@
+CREATE TABLE chat_name_style
+(
+    id    BIGINT PRIMARY KEY,
+    style TEXT NOT NULL
+);
+
+CREATE TABLE user_name
+(
+    id       BIGINT PRIMARY KEY,
+    style    TEXT,
+    nickname TEXT
+);
@@ -0,0 +1,13 @@
+CREATE TABLE reply_booking
+(
+    chat_id           BIGINT      NOT NULL,
+    command_id        INTEGER     NOT NULL,
+    command_sender_id BIGINT      NOT NULL,
+    reply_id          INTEGER     NOT NULL,
+    reply_sender_id   BIGINT      NOT NULL,
+    created_at        TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (chat_id, command_id)
+);
+
+CREATE UNIQUE INDEX reply_booking_reply ON reply_booking (chat_id, reply_id);
+CREATE INDEX reply_booking_created_at ON reply_booking (created_at);
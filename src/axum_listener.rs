@@ -22,17 +22,15 @@ where
     tokio::spawn(async move {
         let tcp_listener = tokio::net::TcpListener::bind(address)
             .await
-            .map_err(|err| {
+            .inspect_err(|_| {
                 stop_token.stop();
-                err
             })
             .expect("Couldn't bind to the address");
         axum::serve(tcp_listener, app)
             .with_graceful_shutdown(stop_flag)
             .await
-            .map_err(|err| {
+            .inspect_err(|_| {
                 stop_token.stop();
-                err
             })
             .expect("Axum server error");
     });
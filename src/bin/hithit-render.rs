@@ -0,0 +1,286 @@
+#![allow(clippy::non_ascii_literal)]
+
+use std::io::{self, Read};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, FixedOffset};
+use clap::Parser;
+use hithit_bot::command::CommandParser;
+use hithit_bot::elaborator::{elaborate, elaborate_error};
+use hithit_bot::formatter::{FormatContext, Target, DATE_FORMAT, TIME_FORMAT};
+use hithit_bot::limits::Limits;
+use hithit_bot::locale::Locale;
+use hithit_bot::offset::CharOffset;
+use hithit_bot::segments::{NameStyle, Segment, Segments};
+use hithit_bot::time::parse_utc_offset;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use teloxide::types::{MessageEntity, User, UserId};
+
+/// Renders a hit command offline, the same way the bot would reply to it.
+///
+/// The reply is printed as a JSON object with `text` and `entities` fields.
+/// Exits with 1 if the message is not a hit command or the input is invalid, and with 2 if the
+/// template is invalid.
+#[derive(Parser)]
+#[command(name = "hithit-render")]
+struct Args {
+    /// Message text, e.g. `/打`. If omitted, a JSON object with `text` and `entities` fields
+    /// (such as a Telegram `Message`) is read from stdin.
+    text: Option<String>,
+    /// JSON array of Telegram `MessageEntity` for the message text.
+    #[arg(short, long)]
+    entities: Option<String>,
+    /// Name of the sender.
+    #[arg(short, long, default_value = "Alice")]
+    sender: String,
+    /// How users are named, one of `first`, `full`, `username` or `nickname`.
+    #[arg(long, default_value = "full", value_parser = parse_name_style)]
+    name_style: NameStyle,
+    /// Name of the user being replied to. Without it the sender hits themselves.
+    #[arg(short, long)]
+    receiver: Option<String>,
+    /// Text of the replied message, for `{quote}`.
+    #[arg(long)]
+    quote: Option<String>,
+    /// How many characters of the replied message `{quote}` keeps.
+    #[arg(long, default_value_t = Limits::DEFAULT_QUOTE_LIMIT)]
+    quote_limit: usize,
+    /// Title of the chat, for `{chat}`.
+    #[arg(long)]
+    chat: Option<String>,
+    /// Prefix for ASCII commands in naive mode.
+    #[arg(long, default_value_t = '^')]
+    prefix: char,
+    /// Username of the bot, to accept `/explain@<username>`.
+    #[arg(long)]
+    bot_username: Option<String>,
+    /// Name of the bot, for `{bot}`.
+    #[arg(long, default_value = "hithit")]
+    bot_name: String,
+    /// When the message was sent, in seconds since the Unix epoch, for `{time}` and `{date}`.
+    /// Defaults to now.
+    #[arg(long)]
+    timestamp: Option<i64>,
+    /// Timezone of the chat, e.g. `+08:00` or `UTC+8`, as `/timezone` takes it.
+    #[arg(long, default_value = "+00:00", value_parser = parse_timezone)]
+    timezone: FixedOffset,
+    /// Render as if compatibility mode was enabled in the chat.
+    #[arg(long)]
+    compatibility: bool,
+    /// Locale of the chat, one of `zh-hans`, `yue`, `ja` or `en`.
+    #[arg(long, default_value = "zh-hans", value_parser = parse_locale)]
+    locale: Locale,
+    /// How many times a single user may be pinged in the reply.
+    #[arg(long, default_value_t = Limits::DEFAULT_MAX_PINGS)]
+    max_pings: usize,
+    /// Seed for picking random alternatives.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Print the text of the reply only.
+    #[arg(long)]
+    plain: bool,
+}
+
+#[derive(Deserialize)]
+struct Input {
+    text: String,
+    #[serde(default)]
+    entities: Vec<MessageEntity>,
+}
+
+#[derive(Serialize)]
+struct Output {
+    text: String,
+    entities: Vec<MessageEntity>,
+}
+
+fn parse_locale(name: &str) -> Result<Locale, String> {
+    Locale::from_name(name).ok_or_else(|| format!("unknown locale {name}"))
+}
+
+fn parse_timezone(offset: &str) -> Result<FixedOffset, String> {
+    parse_utc_offset(offset)
+        .and_then(FixedOffset::east_opt)
+        .ok_or_else(|| format!("invalid timezone {offset}"))
+}
+
+fn parse_name_style(name: &str) -> Result<NameStyle, String> {
+    NameStyle::from_name(name).ok_or_else(|| format!("unknown name style {name}"))
+}
+
+fn user(id: u64, name: String) -> User {
+    User {
+        id: UserId(id),
+        is_bot: false,
+        first_name: name,
+        last_name: None,
+        username: None,
+        language_code: None,
+        is_premium: false,
+        added_to_attachment_menu: false,
+    }
+}
+
+fn read_input(args: &Args) -> Result<Input, String> {
+    let input = read_unchecked_input(args)?;
+    let length = input.text.encode_utf16().count();
+    for entity in &input.entities {
+        if entity
+            .offset
+            .checked_add(entity.length)
+            .is_none_or(|end| end > length)
+        {
+            return Err(format!(
+                "entity at offset {} with length {} runs past the end of the text ({length} UTF-16 code units)",
+                entity.offset, entity.length
+            ));
+        }
+    }
+    Ok(input)
+}
+
+fn read_unchecked_input(args: &Args) -> Result<Input, String> {
+    if let Some(text) = &args.text {
+        let entities = match &args.entities {
+            Some(entities) => {
+                serde_json::from_str(entities).map_err(|e| format!("invalid entities: {e}"))?
+            }
+            None => vec![],
+        };
+        return Ok(Input {
+            text: text.clone(),
+            entities,
+        });
+    }
+
+    let mut buffer = String::new();
+    io::stdin()
+        .read_to_string(&mut buffer)
+        .map_err(|e| format!("cannot read stdin: {e}"))?;
+    serde_json::from_str(&buffer).map_err(|e| format!("invalid input: {e}"))
+}
+
+fn sent_at(args: &Args) -> Result<DateTime<FixedOffset>, String> {
+    let timestamp = args.timestamp.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs().cast_signed())
+    });
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|date| date.with_timezone(&args.timezone))
+        .ok_or_else(|| format!("invalid timestamp {timestamp}"))
+}
+
+fn build_format_ctx(
+    args: &Args,
+    mentions: &[Segment],
+    date: DateTime<FixedOffset>,
+) -> FormatContext {
+    let sender = user(1, args.sender.clone());
+    let me = Segment::from_user_with_name(sender.clone(), String::from(args.locale.reflexive));
+    let name = |user: User| Segment::from_user_styled(user, args.name_style, None);
+    let ctx = match &args.receiver {
+        Some(receiver) => {
+            let receiver =
+                Segment::from_user_styled(user(2, receiver.clone()), args.name_style, None);
+            FormatContext::new(name(sender), receiver.clone(), me)
+                .with_receivers([receiver].into_iter().chain(mentions.to_vec()).collect())
+                .with_target(Target::Other, true)
+        }
+        None if !mentions.is_empty() => FormatContext::new(name(sender), me.clone(), me)
+            .with_receivers(mentions.to_vec())
+            .with_target(Target::Other, false),
+        None => FormatContext::new(name(sender), me.clone(), me).with_target(Target::Myself, false),
+    };
+    let bot = User {
+        is_bot: true,
+        ..user(0, args.bot_name.clone())
+    };
+    let ctx = ctx
+        .with_locale(args.locale)
+        .with_var("time", Segment::plain(date.format(TIME_FORMAT).to_string()))
+        .with_var("date", Segment::plain(date.format(DATE_FORMAT).to_string()))
+        .with_var("bot", name(bot));
+    let ctx = match &args.chat {
+        Some(chat) => ctx.with_var("chat", Segment::plain(chat.as_str())),
+        None => ctx,
+    };
+    let ctx = match &args.quote {
+        Some(quote) => ctx.with_rich_var(
+            "quote",
+            Segments::from([Segment::plain(quote.as_str())])
+                .trim()
+                .truncate_to(CharOffset(args.quote_limit), "…"),
+        ),
+        None => ctx,
+    };
+    match args.seed {
+        Some(seed) => ctx.with_rng(StdRng::seed_from_u64(seed)),
+        None => ctx,
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let (input, date) = match read_input(&args).and_then(|input| Ok((input, sent_at(&args)?))) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let commands = args.bot_username.as_deref().map_or_else(
+        || CommandParser::new(args.prefix),
+        |username| CommandParser::new(args.prefix).with_bot_username(username),
+    );
+    let parser = commands
+        .parse(&input.text, &input.entities, args.compatibility)
+        .map(|parser| parser.with_locale(args.locale));
+    let output = match parser
+        .as_ref()
+        .map(|parser| (parser, parser.try_as_formatter()))
+    {
+        Some((parser, Ok(Some(formatter)))) => formatter
+            .format(&build_format_ctx(&args, parser.mentions(), date))
+            .map_err(Into::into),
+        Some((_, Err(e))) => Err(e),
+        None | Some((_, Ok(None))) => {
+            eprintln!("not a hit command");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let failed = output.is_err();
+    let reply = if CommandParser::is_explain(&input.text) {
+        elaborate(&input.text, &input.entities, output)
+    } else {
+        output.unwrap_or_else(|e| elaborate_error(&input.text, &e).into())
+    };
+    let reply = Limits::default()
+        .with_max_pings(args.max_pings)
+        .apply(reply, "…");
+
+    if args.plain {
+        println!("{}", reply.text());
+    } else {
+        let output = Output {
+            text: reply.text(),
+            entities: reply.entities(),
+        };
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&output).expect("reply is serializable")
+        );
+    }
+
+    if failed {
+        ExitCode::from(2)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
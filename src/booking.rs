@@ -0,0 +1,293 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use lru_cache::LruCache;
+use teloxide::types::{ChatId, Message, MessageId, UserId};
+
+/// A message, by where it is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct MessageKey {
+    pub chat_id: ChatId,
+    pub message_id: MessageId,
+}
+
+impl From<&Message> for MessageKey {
+    fn from(msg: &Message) -> Self {
+        Self {
+            chat_id: msg.chat.id,
+            message_id: msg.id,
+        }
+    }
+}
+
+/// One side of a booking: a command or the reply to it, and who sent it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Booked {
+    pub message: MessageKey,
+    pub sender: UserId,
+}
+
+impl Booked {
+    /// Returns `None` for messages without a sender, such as channel posts.
+    pub fn from_message(msg: &Message) -> Option<Self> {
+        Some(Self {
+            message: msg.into(),
+            sender: msg.from.as_ref()?.id,
+        })
+    }
+}
+
+/// How a [`BookingStore`] is doing.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CacheStats {
+    pub len: usize,
+    pub capacity: usize,
+    /// Replies that can be looked up. The same as `len` unless bookings are being changed.
+    pub replies: usize,
+    /// Lookups that found a booking.
+    pub hits: u64,
+    /// Lookups that didn't.
+    pub misses: u64,
+    /// Bookings dropped because the store was full or they expired.
+    pub evictions: u64,
+}
+
+struct Entry {
+    command_sender: UserId,
+    reply: MessageId,
+    reply_sender: UserId,
+    booked_at: Instant,
+}
+
+// A booking's forward entry lives in the shard of its command and its reverse entry in the shard
+// of its reply, so one busy chat is spread over every shard. Only one shard is locked at a time.
+struct Shard {
+    // Commands to their replies, least recently used first.
+    forward: LruCache<MessageKey, Entry>,
+    // Replies to their commands.
+    reverse: HashMap<MessageKey, MessageId>,
+}
+
+fn reply_of(command: &MessageKey, entry: &Entry) -> MessageKey {
+    MessageKey {
+        chat_id: command.chat_id,
+        message_id: entry.reply,
+    }
+}
+
+/// Which reply of the bot answers which command, kept in memory.
+///
+/// Bookings are spread over shards by message, each with its own lock and LRU order, so that
+/// busy chats don't wait on each other and a single chat can use the whole capacity. Bookings
+/// older than the max age are never returned.
+pub struct BookingStore {
+    shards: Box<[Mutex<Shard>]>,
+    max_age: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl BookingStore {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        let shards = std::thread::available_parallelism().map_or(1, |n| n.get() * 4);
+        Self::with_shards(capacity, max_age, shards)
+    }
+
+    /// Splits `capacity` over `shards` shards, each holding at least one booking.
+    pub fn with_shards(capacity: usize, max_age: Duration, shards: usize) -> Self {
+        let shards = shards.max(1);
+        let shard_capacity = capacity.div_ceil(shards).max(1);
+        Self {
+            shards: (0..shards)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        forward: LruCache::new(shard_capacity),
+                        reverse: HashMap::with_capacity(shard_capacity),
+                    })
+                })
+                .collect(),
+            max_age,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub const fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    fn shard(&self, message: &MessageKey) -> MutexGuard<'_, Shard> {
+        let mut hasher = DefaultHasher::new();
+        message.hash(&mut hasher);
+        #[allow(clippy::cast_possible_truncation)]
+        let index = hasher.finish() as usize % self.shards.len();
+        // A panic while holding the lock can't leave a shard half updated in a harmful way.
+        self.shards[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn count(&self, found: bool) {
+        let counter = if found { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Drops the reverse entry of `reply`, unless it has been booked for another command since.
+    fn unlink(&self, reply: &MessageKey, command: MessageId) {
+        let mut shard = self.shard(reply);
+        if shard.reverse.get(reply) == Some(&command) {
+            shard.reverse.remove(reply);
+        }
+    }
+
+    /// Books `reply` as the answer to `command`, which have to be in the same chat.
+    pub fn book(&self, command: Booked, reply: Booked) {
+        self.book_at(command, reply, Instant::now());
+    }
+
+    /// Like [`book`](Self::book), for a reply sent at `booked_at`.
+    pub fn book_at(&self, command: Booked, reply: Booked, booked_at: Instant) {
+        debug_assert_eq!(command.message.chat_id, reply.message.chat_id);
+        // The reverse entry goes first, so that whoever removes the forward entry afterwards, be
+        // it `forget` or an eviction, finds it and removes it too.
+        self.shard(&reply.message)
+            .reverse
+            .insert(reply.message, command.message.message_id);
+        let (replaced, evicted) = {
+            let mut shard = self.shard(&command.message);
+            let replaced = shard
+                .forward
+                .remove(&command.message)
+                .map(|entry| reply_of(&command.message, &entry));
+            let evicted = if replaced.is_none() && shard.forward.len() >= shard.forward.capacity() {
+                shard.forward.remove_lru()
+            } else {
+                None
+            };
+            shard.forward.insert(
+                command.message,
+                Entry {
+                    command_sender: command.sender,
+                    reply: reply.message.message_id,
+                    reply_sender: reply.sender,
+                    booked_at,
+                },
+            );
+            (replaced, evicted)
+        };
+        if let Some(replaced) = replaced.filter(|replaced| *replaced != reply.message) {
+            self.unlink(&replaced, command.message.message_id);
+        }
+        if let Some((lru, entry)) = evicted {
+            self.unlink(&reply_of(&lru, &entry), lru.message_id);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Looks up `command`, dropping its booking if it has expired.
+    fn get<T>(&self, command: &MessageKey, f: impl FnOnce(&Entry) -> T) -> Option<T> {
+        let expired = {
+            let mut shard = self.shard(command);
+            let entry = shard.forward.get_mut(command)?;
+            if entry.booked_at.elapsed() <= self.max_age {
+                return Some(f(entry));
+            }
+            shard.forward.remove(command)?
+        };
+        self.unlink(&reply_of(command, &expired), command.message_id);
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// The reply to `command`, if there is one.
+    pub fn forward_lookup(&self, command: MessageKey) -> Option<Booked> {
+        let reply = self.get(&command, |entry| Booked {
+            message: reply_of(&command, entry),
+            sender: entry.reply_sender,
+        });
+        self.count(reply.is_some());
+        reply
+    }
+
+    /// The command `reply` answers, if it is a reply of the bot.
+    pub fn reverse_lookup(&self, reply: MessageKey) -> Option<Booked> {
+        let command = self.shard(&reply).reverse.get(&reply).copied();
+        let booked = command.and_then(|message_id| {
+            let command = MessageKey {
+                chat_id: reply.chat_id,
+                message_id,
+            };
+            // The command may have been rebooked with another reply in the meantime.
+            self.get(&command, |entry| {
+                (entry.reply == reply.message_id).then_some(Booked {
+                    message: command,
+                    sender: entry.command_sender,
+                })
+            })
+            .flatten()
+        });
+        self.count(booked.is_some());
+        booked
+    }
+
+    pub fn forget(&self, command: MessageKey) {
+        let removed = self.shard(&command).forward.remove(&command);
+        if let Some(entry) = removed {
+            self.unlink(&reply_of(&command, &entry), command.message_id);
+        }
+    }
+
+    /// Drops the bookings that are older than the max age.
+    pub fn remove_expired(&self) {
+        for shard in &*self.shards {
+            let expired: Vec<_> = {
+                let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+                let commands: Vec<_> = shard
+                    .forward
+                    .iter()
+                    .filter(|(_, entry)| entry.booked_at.elapsed() > self.max_age)
+                    .map(|(command, _)| *command)
+                    .collect();
+                commands
+                    .into_iter()
+                    .filter_map(|command| {
+                        let entry = shard.forward.remove(&command)?;
+                        Some((command, reply_of(&command, &entry)))
+                    })
+                    .collect()
+            };
+            for (command, reply) in expired {
+                self.unlink(&reply, command.message_id);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (len, capacity, replies) =
+            self.shards
+                .iter()
+                .fold((0, 0, 0), |(len, capacity, replies), shard| {
+                    let shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+                    (
+                        len + shard.forward.len(),
+                        capacity + shard.forward.capacity(),
+                        replies + shard.reverse.len(),
+                    )
+                });
+        CacheStats {
+            len,
+            capacity,
+            replies,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use teloxide::types::MessageEntity;
+
+use crate::offset::{CharOffset, TextOffset};
+use crate::parser::Parser;
+use crate::segments::Segments;
+
+pub const EXPLAIN_COMMAND: &str = "/explain";
+
+/// Recognises hit commands such as `/打`, `/^aww` and `/explain <template>`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommandParser {
+    prefix: char,
+    explain_extended: Option<String>,
+}
+
+impl CommandParser {
+    /// `prefix` marks ASCII commands that should be read in naive mode, e.g. `^` in `/^aww`.
+    pub const fn new(prefix: char) -> Self {
+        Self {
+            prefix,
+            explain_extended: None,
+        }
+    }
+    /// Also accepts `/explain@<username>`.
+    pub fn with_bot_username(mut self, username: &str) -> Self {
+        self.explain_extended = Some(format!("{EXPLAIN_COMMAND}@{username}"));
+        self
+    }
+    pub fn is_explain(text: &str) -> bool {
+        text.starts_with(EXPLAIN_COMMAND)
+    }
+    /// Strips the command from the message and returns a parser for the rest.
+    ///
+    /// In compatibility mode naive parsing is only used for `/explain`, so that the bot doesn't
+    /// react to commands meant for other bots.
+    pub fn parse(
+        &self,
+        text: &str,
+        entities: &[MessageEntity],
+        compatibility: bool,
+    ) -> Option<Parser> {
+        if !text.starts_with('/') {
+            return None;
+        }
+
+        match &self.explain_extended {
+            Some(explain) if text.starts_with(explain.as_str()) => parser_after(
+                text,
+                entities,
+                CharOffset::end_of(explain) + CharOffset(1),
+                true,
+            ),
+            _ if text.starts_with(EXPLAIN_COMMAND) => {
+                let head = CharOffset::end_of(EXPLAIN_COMMAND) + CharOffset(1);
+                parser_after(text, entities, head, true)
+            }
+            _ => text.chars().nth(1).and_then(|chr| {
+                if chr.len_utf8() > 1 {
+                    parser_after(text, entities, CharOffset(1), !compatibility)
+                } else if chr == self.prefix {
+                    parser_after(text, entities, CharOffset(2), !compatibility)
+                } else {
+                    parser_after(text, entities, CharOffset(1), false)
+                }
+            }),
+        }
+    }
+}
+
+fn parser_after(
+    text: &str,
+    entities: &[MessageEntity],
+    head: CharOffset,
+    try_naive: bool,
+) -> Option<Parser> {
+    let segments = Segments::build(text, entities).drain_head(head)?;
+    let offset = text.encode_utf16().count() - segments.utf16_len();
+    Some(
+        Parser::new(segments, try_naive)
+            .with_offset(offset)
+            .take_mentions(),
+    )
+}
@@ -1,13 +1,12 @@
-use std::error::Error;
-
 use maplit::hashset;
-use teloxide::types::{Message, MessageEntity, MessageEntityKind};
+use teloxide::types::{MessageEntity, MessageEntityKind};
 
-use crate::error::Result;
+use crate::error::{ExportedError, Result};
 use crate::segments::{Segment, Segments};
 
-pub fn elaborate_error(err: impl Error) -> impl IntoIterator<Item = Segment> {
-    [
+/// Describes `err` and highlights the offending part of `text`, the message it came from.
+pub fn elaborate_error(text: &str, err: &ExportedError) -> impl IntoIterator<Item = Segment> {
+    let mut segments = vec![
         Segment {
             text: String::from("An error occurred while processing your template.\n"),
             kind: hashset!(MessageEntityKind::Bold),
@@ -16,7 +15,25 @@ pub fn elaborate_error(err: impl Error) -> impl IntoIterator<Item = Segment> {
             text: err.to_string(),
             kind: hashset!(MessageEntityKind::Code),
         },
-    ]
+    ];
+    let span = err.span();
+    if !span.is_empty() {
+        segments.push(Segment {
+            text: String::from("\n\n"),
+            kind: hashset!(),
+        });
+        segments.extend(
+            Segments::build(
+                text,
+                &[
+                    MessageEntity::underline(span.start, span.len()),
+                    MessageEntity::bold(span.start, span.len()),
+                ],
+            )
+            .drain(..),
+        );
+    }
+    segments
 }
 
 fn elaborate_input(
@@ -65,15 +82,14 @@ fn elaborate_output(segments: &Segments) -> impl IntoIterator<Item = Segment> {
     ]
 }
 
-pub fn elaborate(update: &Message, output: Result<Segments>) -> Segments {
-    let text = update.text().expect("must be text message");
-    let entities = update.entities().expect("must be text message");
+/// Explains how a message was parsed and rendered, for the `/explain` command.
+pub fn elaborate(text: &str, entities: &[MessageEntity], output: Result<Segments>) -> Segments {
     let input = Segments::build(text, entities);
 
     let elaborated_input = elaborate_input(text, entities, &input).into_iter();
 
     match output {
         Ok(output) => elaborated_input.chain(elaborate_output(&output)).into(),
-        Err(e) => elaborated_input.chain(elaborate_error(e)).into(),
+        Err(e) => elaborated_input.chain(elaborate_error(text, &e)).into(),
     }
 }
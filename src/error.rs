@@ -1,47 +1,31 @@
+use std::ops::Range;
+
 use thiserror::Error;
 
+pub use crate::markup::MarkupError as Markup;
 pub use crate::parser::ParseError as Parse;
 
-pub type Result<T, E = Error> = std::result::Result<T, E>;
+pub type Result<T, E = ExportedError> = std::result::Result<T, E>;
 
+/// Errors raised while filling a template with a [`FormatContext`](crate::formatter::FormatContext).
 #[derive(Debug, Error)]
 pub enum Format {
-    #[error("index {0} not found")]
-    InvalidIndex(usize),
-    #[error("key {0} not found")]
-    InvalidKey(String),
-}
-
-#[derive(Debug, Error)]
-pub enum Error {
-    #[error("this message is not intended to be handled by this bot")]
-    ShouldNotHandle,
-    #[error("format error: {0}")]
-    Format(#[from] Format),
-    #[error("parse error: {0}")]
-    Parse(#[from] Box<Parse>),
-}
-
-impl From<Parse> for Error {
-    fn from(value: Parse) -> Self {
-        Self::Parse(Box::new(value))
-    }
-}
-
-pub trait ErrorExt<T> {
-    fn lift_should_not_handle(self) -> Result<T, Error>;
+    #[error("index {index} not found at position {}", span.start)]
+    InvalidIndex { index: usize, span: Range<usize> },
+    #[error("key {key} not found at position {}", span.start)]
+    InvalidKey { key: String, span: Range<usize> },
 }
 
-impl<T> ErrorExt<Self> for Result<T, Error> {
-    fn lift_should_not_handle(self) -> Result<Self, Error> {
+impl Format {
+    /// The offending hole, in UTF-16 offsets of the original message.
+    pub fn span(&self) -> Range<usize> {
         match self {
-            Ok(t) => Ok(Ok(t)),
-            Err(Error::ShouldNotHandle) => Err(Error::ShouldNotHandle),
-            Err(e) => Ok(Err(e)),
+            Self::InvalidIndex { span, .. } | Self::InvalidKey { span, .. } => span.clone(),
         }
     }
 }
 
+/// Any error the template engine may report back to the user.
 #[derive(Debug, Error)]
 pub enum ExportedError {
     #[error("format error: {0}")]
@@ -55,3 +39,13 @@ impl From<Parse> for ExportedError {
         Self::Parse(Box::new(value))
     }
 }
+
+impl ExportedError {
+    /// The offending part of the template, in UTF-16 offsets of the original message.
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Self::Format(e) => e.span(),
+            Self::Parse(e) => e.span.clone(),
+        }
+    }
+}
@@ -1,13 +1,23 @@
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::ops::Range;
+use std::str::FromStr;
 
 use maplit::hashmap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use teloxide::types::MessageEntityKind;
 
 use crate::error::Format as FormatError;
+use crate::locale::Locale;
 use crate::segments::{Segment, Segments};
 
-const TERMINATION_MARKS: [char; 11] = ['。', '，', '！', '？', '；', '、', '.', ',', '!', '?', ';'];
+/// `strftime` format of `{time}`.
+pub const TIME_FORMAT: &str = "%H:%M";
+/// `strftime` format of `{date}`.
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
 
+/// What a hole refers to: the next positional argument, an indexed one, or a named one.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum HoleIdent {
     Anonymous,
@@ -15,23 +25,167 @@ pub enum HoleIdent {
     Named(String),
 }
 
+/// A filter applied to the value of a hole, e.g. `{r|plain}`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Filter {
+    Plain,
+    Upper,
+    Lower,
+    Reverse,
+    First,
+}
+
+impl FromStr for Filter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "plain" => Self::Plain,
+            "upper" => Self::Upper,
+            "lower" => Self::Lower,
+            "reverse" => Self::Reverse,
+            "first" => Self::First,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl Filter {
+    /// Applies the filter, keeping the entity kinds of `segment` unless stated otherwise.
+    pub fn apply(self, mut segment: Segment) -> Segment {
+        match self {
+            // Keep the text but stop pinging the user.
+            Self::Plain => segment.kind.retain(|kind| {
+                !matches!(
+                    kind,
+                    MessageEntityKind::TextMention { .. } | MessageEntityKind::Mention
+                )
+            }),
+            Self::Upper => segment.text = segment.text.to_uppercase(),
+            Self::Lower => segment.text = segment.text.to_lowercase(),
+            Self::Reverse => segment.text = segment.text.chars().rev().collect(),
+            Self::First => {
+                let first_name = segment.kind.iter().find_map(|kind| match kind {
+                    MessageEntityKind::TextMention { user }
+                        if segment.text.starts_with(&user.first_name) =>
+                    {
+                        Some(user.first_name.clone())
+                    }
+                    _ => None,
+                });
+                segment.text = first_name.unwrap_or_else(|| {
+                    segment
+                        .text
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string()
+                });
+            }
+        }
+        segment
+    }
+    /// Applies the filter to a value made of several segments.
+    pub fn apply_all(self, segments: Vec<Segment>) -> Vec<Segment> {
+        match self {
+            Self::Reverse => segments.into_iter().rev().map(|s| self.apply(s)).collect(),
+            Self::First => segments
+                .into_iter()
+                .take(1)
+                .map(|s| self.apply(s))
+                .collect(),
+            _ => segments.into_iter().map(|s| self.apply(s)).collect(),
+        }
+    }
+}
+
+/// Who the sender is hitting.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Target {
+    Myself,
+    Bot,
+    Other,
+}
+
+/// The condition of a `{?condition}…{:}…{/}` block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Condition {
+    Myself,
+    Bot,
+    Other,
+    Reply,
+}
+
+impl FromStr for Condition {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "self" => Self::Myself,
+            "bot" => Self::Bot,
+            "other" => Self::Other,
+            "reply" => Self::Reply,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// A node of a parsed template.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Token {
     Segment(Segment),
     Hole {
         kind: HashSet<MessageEntityKind>,
         ident: HoleIdent,
+        fallback: Option<String>,
+        filters: Vec<Filter>,
+        span: Range<usize>,
+    },
+    Conditional {
+        condition: Condition,
+        then: Vec<Token>,
+        otherwise: Vec<Token>,
+    },
+    Choice {
+        branches: Vec<Vec<Token>>,
     },
 }
 
+/// Variables the bot always provides. `{r|uppr}` is a typo of a filter rather than a choice
+/// between `r` and `uppr`, because `r` is one of them.
+pub const VARIABLES: [&str; 13] = [
+    "sender",
+    "receiver",
+    "receivers",
+    "s",
+    "r",
+    "penetrator",
+    "self",
+    "me",
+    "this",
+    "quote",
+    "time",
+    "date",
+    "bot",
+];
+
+/// The values a template is rendered with.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct FormatContext {
-    indexed_args: Vec<Segment>,
-    named_args: HashMap<&'static str, Segment>,
+    indexed_args: Vec<Segments>,
+    named_args: HashMap<&'static str, Segments>,
+    target: Target,
+    reply: bool,
+    locale: Locale,
+    rng: StdRng,
 }
 
 impl FormatContext {
+    /// `me` is how the sender refers to themselves, e.g. `自己`.
     pub fn new(sender: Segment, receiver: Segment, me: Segment) -> Self {
+        let sender = Segments::from([sender]);
+        let receiver = Segments::from([receiver]);
+        let me = Segments::from([me]);
         Self {
             indexed_args: vec![sender.clone(), receiver.clone()],
             named_args: hashmap! {
@@ -44,10 +198,81 @@ impl FormatContext {
                 "me" => me.clone(),
                 "this" => me
             },
+            target: Target::Other,
+            reply: false,
+            locale: Locale::default(),
+            rng: StdRng::from_os_rng(),
+        }
+    }
+    /// Adds a named variable, or replaces a built-in one.
+    pub fn with_var(self, name: &'static str, value: Segment) -> Self {
+        self.with_rich_var(name, Segments::from([value]))
+    }
+    /// Like [`with_var`](Self::with_var), for values made of several runs of formatting.
+    pub fn with_rich_var(mut self, name: &'static str, value: Segments) -> Self {
+        self.named_args.insert(name, value);
+        self
+    }
+    pub const fn with_locale(mut self, locale: Locale) -> Self {
+        self.locale = locale;
+        self
+    }
+    /// Replaces the RNG used to pick alternatives, e.g. with a seeded one.
+    pub fn with_rng(mut self, rng: StdRng) -> Self {
+        self.rng = rng;
+        self
+    }
+    /// Sets who is being hit and whether the command replies to a message, for conditional blocks.
+    pub const fn with_target(mut self, target: Target, reply: bool) -> Self {
+        self.target = target;
+        self.reply = reply;
+        self
+    }
+    /// Replaces the receiver with several ones, e.g. the replied user and those mentioned in the
+    /// command. They fill indexed holes from 1 on, and `{receiver}` is the first of them.
+    pub fn with_receivers(mut self, receivers: Vec<Segment>) -> Self {
+        let Some(first) = receivers.first() else {
+            return self;
+        };
+        let first = Segments::from([first.clone()]);
+        for name in ["receiver", "r"] {
+            self.named_args.insert(name, first.clone());
+        }
+        self.indexed_args.truncate(1);
+        self.indexed_args.extend(
+            receivers
+                .into_iter()
+                .map(|receiver| Segments::from([receiver])),
+        );
+        self
+    }
+    /// All receivers joined into one phrase, e.g. `A、B 和 C`. This is `{receivers}`.
+    pub fn receivers(&self) -> Segments {
+        let receivers = &self.indexed_args[1..];
+        let mut output = vec![];
+        for (i, receiver) in receivers.iter().enumerate() {
+            if i > 0 {
+                output.push(Segment::plain(if i + 1 == receivers.len() {
+                    self.locale.conjunction
+                } else {
+                    self.locale.separator
+                }));
+            }
+            output.extend(receiver.iter().cloned());
+        }
+        output.into()
+    }
+    fn test(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::Myself => self.target == Target::Myself,
+            Condition::Bot => self.target == Target::Bot,
+            Condition::Other => self.target == Target::Other,
+            Condition::Reply => self.reply,
         }
     }
 }
 
+/// A parsed template.
 #[derive(Debug, Default, Clone)]
 pub struct Formatter {
     pub data: Vec<Token>,
@@ -62,62 +287,221 @@ impl Formatter {
     pub const fn named_holes(&self) -> &HashSet<String> {
         &self.named
     }
-    pub fn format(&self, ctx: &FormatContext) -> Result<Segments, FormatError> {
+    pub fn has_control_flow(&self) -> bool {
         self.data
             .iter()
-            .map(fill_placeholder(ctx))
-            .collect::<Result<VecDeque<_>, _>>()
-            .map(Segments::new)
-            .map(Segments::trim)
-            .map(add_exclaim_mark)
-    }
-}
-
-fn fill_placeholder(
-    ctx: &FormatContext,
-) -> impl FnMut(&Token) -> Result<Segment, FormatError> + '_ {
-    let mut implicit_idx: usize = 0;
-    move |token| match token {
-        Token::Segment(segment) => Ok(segment.clone()),
-        Token::Hole { kind, ident } => match ident {
-            HoleIdent::Anonymous => ctx
-                .indexed_args
-                .get(implicit_idx)
-                .ok_or(FormatError::InvalidIndex(implicit_idx))
-                .inspect(|_| {
-                    implicit_idx += 1;
-                }),
-            HoleIdent::Indexed(idx) => ctx
-                .indexed_args
-                .get(*idx)
-                .ok_or(FormatError::InvalidIndex(*idx)),
-            HoleIdent::Named(name) => ctx
+            .any(|token| matches!(token, Token::Conditional { .. } | Token::Choice { .. }))
+    }
+    /// Usernames of the `@username` mentions written in the template itself.
+    pub fn mentioned_usernames(&self) -> HashSet<String> {
+        let mut usernames = HashSet::new();
+        visit_segments(&self.data, &mut |segment| {
+            if let Some(username) = segment.mentioned_username() {
+                usernames.insert(username.to_string());
+            }
+        });
+        usernames
+    }
+    /// Replaces `@username` mentions written in the template with what `resolve` returns for
+    /// them, e.g. mentions of the users by name. Values of holes are left alone, so
+    /// `{sender_username}` stays as is.
+    pub fn resolve_mentions(&mut self, mut resolve: impl FnMut(&str) -> Option<Segment>) {
+        visit_segments_mut(&mut self.data, &mut |segment| {
+            if let Some(resolved) = segment.mentioned_username().and_then(&mut resolve) {
+                *segment = mem::take(segment).resolve_mention(resolved);
+            }
+        });
+    }
+    /// Whether the template uses the receivers besides the first one, or all of them at once.
+    pub fn refers_to_receivers(&self) -> bool {
+        self.indexed > 1
+            || ["r", "receiver", "receivers"]
+                .iter()
+                .any(|name| self.named.contains(*name))
+    }
+    /// Whether the input contains any template syntax at all.
+    pub fn is_templated(&self) -> bool {
+        self.indexed_holes() > 0 || !self.named_holes().is_empty() || self.has_control_flow()
+    }
+    /// Renders the template, appending an exclamation mark unless it already ends with one.
+    ///
+    /// Adjacent segments with the same formatting are merged in the output.
+    pub fn format(&self, ctx: &FormatContext) -> Result<Segments, FormatError> {
+        let mut output = VecDeque::new();
+        Filler::new(ctx).fill(&self.data, &mut output)?;
+        Ok(add_exclaim_mark(Segments::new(output).trim(), &ctx.locale).normalize())
+    }
+}
+
+fn visit_segments(tokens: &[Token], f: &mut impl FnMut(&Segment)) {
+    for token in tokens {
+        match token {
+            Token::Segment(segment) => f(segment),
+            Token::Hole { .. } => {}
+            Token::Conditional {
+                then, otherwise, ..
+            } => {
+                visit_segments(then, f);
+                visit_segments(otherwise, f);
+            }
+            Token::Choice { branches } => {
+                for branch in branches {
+                    visit_segments(branch, f);
+                }
+            }
+        }
+    }
+}
+
+fn visit_segments_mut(tokens: &mut [Token], f: &mut impl FnMut(&mut Segment)) {
+    for token in tokens {
+        match token {
+            Token::Segment(segment) => f(segment),
+            Token::Hole { .. } => {}
+            Token::Conditional {
+                then, otherwise, ..
+            } => {
+                visit_segments_mut(then, f);
+                visit_segments_mut(otherwise, f);
+            }
+            Token::Choice { branches } => {
+                for branch in branches {
+                    visit_segments_mut(branch, f);
+                }
+            }
+        }
+    }
+}
+
+struct Filler<'a> {
+    ctx: &'a FormatContext,
+    implicit_idx: usize,
+    rng: StdRng,
+}
+
+impl<'a> Filler<'a> {
+    fn new(ctx: &'a FormatContext) -> Self {
+        Self {
+            ctx,
+            implicit_idx: 0,
+            rng: ctx.rng.clone(),
+        }
+    }
+
+    fn fill(
+        &mut self,
+        tokens: &[Token],
+        output: &mut VecDeque<Segment>,
+    ) -> Result<(), FormatError> {
+        for token in tokens {
+            match token {
+                Token::Segment(segment) => output.push_back(segment.clone()),
+                Token::Hole {
+                    kind,
+                    ident,
+                    fallback,
+                    filters,
+                    span,
+                } => output.extend(
+                    self.fill_placeholder(kind, ident, span)
+                        .or_else(|e| {
+                            fallback.as_ref().map_or(Err(e), |text| {
+                                Ok(vec![Segment {
+                                    kind: kind.clone(),
+                                    text: text.clone(),
+                                }])
+                            })
+                        })
+                        .map(|segments| {
+                            filters
+                                .iter()
+                                .fold(segments, |segments, filter| filter.apply_all(segments))
+                        })?,
+                ),
+                Token::Conditional {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    if self.ctx.test(*condition) {
+                        self.fill(then, output)?;
+                    } else {
+                        self.fill(otherwise, output)?;
+                    }
+                }
+                Token::Choice { branches } => {
+                    let picked = self.rng.random_range(0..branches.len());
+                    self.fill(&branches[picked], output)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_placeholder(
+        &mut self,
+        kind: &HashSet<MessageEntityKind>,
+        ident: &HoleIdent,
+        span: &Range<usize>,
+    ) -> Result<Vec<Segment>, FormatError> {
+        match ident {
+            HoleIdent::Anonymous => {
+                let idx = self.implicit_idx;
+                self.implicit_idx += 1;
+                self.ctx
+                    .indexed_args
+                    .get(idx)
+                    .cloned()
+                    .ok_or_else(|| FormatError::InvalidIndex {
+                        index: idx,
+                        span: span.clone(),
+                    })
+            }
+            HoleIdent::Indexed(idx) => {
+                self.ctx
+                    .indexed_args
+                    .get(*idx)
+                    .cloned()
+                    .ok_or_else(|| FormatError::InvalidIndex {
+                        index: *idx,
+                        span: span.clone(),
+                    })
+            }
+            HoleIdent::Named(name) => self
+                .ctx
                 .named_args
                 .get(name.as_str())
-                .ok_or_else(|| FormatError::InvalidKey(name.clone())),
-        }
-        .map(|segment_to_merge| Segment {
-            kind: kind
-                .clone()
-                .union(&segment_to_merge.kind)
                 .cloned()
-                .collect(),
-            text: segment_to_merge.text.clone(),
-        }),
+                .or_else(|| (name == "receivers").then(|| self.ctx.receivers()))
+                .ok_or_else(|| FormatError::InvalidKey {
+                    key: name.clone(),
+                    span: span.clone(),
+                }),
+        }
+        .map(|segments_to_merge| {
+            segments_to_merge
+                .iter()
+                .map(|segment_to_merge| Segment {
+                    kind: kind.union(&segment_to_merge.kind).cloned().collect(),
+                    text: segment_to_merge.text.clone(),
+                })
+                .collect()
+        })
     }
 }
 
-fn end_with_marks(input: &str) -> bool {
-    TERMINATION_MARKS
+fn end_with_marks(input: &str, locale: &Locale) -> bool {
+    locale
+        .termination_marks
         .iter()
-        .any(|chr| input.chars().last().map_or(false, |ref end| end == chr))
+        .any(|chr| input.chars().last().as_ref() == Some(chr))
 }
 
-fn add_exclaim_mark(mut input: Segments) -> Segments {
+fn add_exclaim_mark(mut input: Segments, locale: &Locale) -> Segments {
     if let Some(segment) = input.back() {
-        if !end_with_marks(segment.text.as_str()) {
+        if !end_with_marks(segment.text.as_str(), locale) {
             input.push_back(Segment {
-                text: String::from("！"),
+                text: String::from(locale.exclamation),
                 kind: HashSet::new(),
             });
             return input;
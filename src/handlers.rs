@@ -1,19 +1,46 @@
 use std::sync::Arc;
 
+use chrono::FixedOffset;
 use eyre::{Result, WrapErr};
-use parking_lot::Mutex;
+use hithit_bot::booking::{Booked, MessageKey};
+use hithit_bot::command::CommandParser;
+use hithit_bot::elaborator::{elaborate, elaborate_error};
+use hithit_bot::locale::Locale;
+use hithit_bot::segments::{NameStyle, Segments};
+use hithit_bot::time::parse_utc_offset;
 use teloxide::payloads::{EditMessageTextSetters, SendMessageSetters};
 use teloxide::requests::Requester;
-use teloxide::types::{Message, ReplyParameters};
+use teloxide::types::{Me, Message, ReplyParameters};
 use teloxide::Bot;
-use tracing::instrument;
+use tracing::{instrument, warn};
 
-use crate::elaborator::{elaborate, elaborate_error};
-use crate::error::{Error, ErrorExt};
-use crate::memory::{MessageMeta, ReplyBooking};
-use crate::process::process;
+use crate::memory::{booked, ReplyBooking};
+use crate::process::{process, ErrorExt};
+use crate::settings;
 use crate::utils::sentry_capture;
-use crate::EXPLAIN_COMMAND;
+use crate::{LIMITS, OPERATORS};
+
+/// How many characters a nickname may have.
+const MAX_NICKNAME_LENGTH: usize = 64;
+
+// Check if the user has the necessary permissions, and tell them if they don't
+async fn check_privileged(bot: &Bot, msg: &Message, action: &str) -> Result<bool> {
+    let Some(user) = &msg.from else {
+        bot.send_message(
+            msg.chat.id,
+            format!("You must be a member of the group to {action}."),
+        )
+        .await?;
+        return Ok(false);
+    };
+    let chat_member = bot.get_chat_member(msg.chat.id, user.id).await?;
+    if !chat_member.is_privileged() {
+        bot.send_message(msg.chat.id, format!("You must be an admin to {action}."))
+            .await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
 
 #[instrument(fields(from = %msg.chat.id, msg = ? msg.text()), skip(msg, bot, pool))]
 pub async fn compatibility_handler(
@@ -31,22 +58,7 @@ pub async fn compatibility_handler(
         return Ok(());
     }
 
-    // Check if the user has the necessary permissions
-    let Some(user) = msg.from else {
-        bot.send_message(
-            msg.chat.id,
-            "You must be a member of the group to set compatibility mode.",
-        )
-        .await?;
-        return Ok(());
-    };
-    let chat_member = bot.get_chat_member(msg.chat.id, user.id).await?;
-    if !chat_member.is_privileged() {
-        bot.send_message(
-            msg.chat.id,
-            "You must be an admin to set compatibility mode.",
-        )
-        .await?;
+    if !check_privileged(&bot, &msg, "set compatibility mode").await? {
         return Ok(());
     }
 
@@ -75,11 +87,317 @@ pub async fn compatibility_handler(
     Ok(())
 }
 
+#[instrument(fields(from = %msg.chat.id, msg = ? msg.text()), skip(msg, bot, pool))]
+pub async fn locale_handler(
+    msg: Message,
+    bot: Bot,
+    name: String,
+    pool: sqlx::PgPool,
+) -> Result<()> {
+    let available = Locale::ALL
+        .iter()
+        .map(|locale| locale.name)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let name = name.trim();
+    if name.is_empty() {
+        let current = settings::locale(&pool, msg.chat.id).await;
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "Current locale is {}. Available locales: {available}.",
+                current.name
+            ),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let Some(locale) = Locale::from_name(name) else {
+        bot.send_message(
+            msg.chat.id,
+            format!("Unknown locale {name}. Available locales: {available}."),
+        )
+        .await?;
+        return Ok(());
+    };
+
+    if !msg.chat.is_private() && !check_privileged(&bot, &msg, "set the locale").await? {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        "INSERT INTO chat_locale (id, locale) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET locale = $2",
+        msg.chat.id.0,
+        locale.name
+    )
+    .execute(&pool)
+    .await?;
+    bot.send_message(msg.chat.id, format!("Locale set to {}.", locale.name))
+        .await?;
+    Ok(())
+}
+
+#[instrument(fields(from = %msg.chat.id, msg = ? msg.text()), skip(msg, bot, pool))]
+pub async fn timezone_handler(
+    msg: Message,
+    bot: Bot,
+    offset: String,
+    pool: sqlx::PgPool,
+) -> Result<()> {
+    if offset.trim().is_empty() {
+        let current = settings::timezone(&pool, msg.chat.id).await;
+        bot.send_message(msg.chat.id, format!("Current timezone is UTC{current}."))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(timezone) = parse_utc_offset(&offset).and_then(FixedOffset::east_opt) else {
+        bot.send_message(
+            msg.chat.id,
+            format!("Invalid timezone {offset}. Use an offset from UTC, e.g. +08:00."),
+        )
+        .await?;
+        return Ok(());
+    };
+
+    if !msg.chat.is_private() && !check_privileged(&bot, &msg, "set the timezone").await? {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        "INSERT INTO chat_timezone (id, utc_offset) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET utc_offset = $2",
+        msg.chat.id.0,
+        timezone.local_minus_utc()
+    )
+    .execute(&pool)
+    .await?;
+    bot.send_message(msg.chat.id, format!("Timezone set to UTC{timezone}."))
+        .await?;
+    Ok(())
+}
+
+#[instrument(fields(from = %msg.chat.id, msg = ? msg.text()), skip(msg, bot, pool))]
+pub async fn name_style_handler(
+    msg: Message,
+    bot: Bot,
+    name: String,
+    pool: sqlx::PgPool,
+) -> Result<()> {
+    let available = NameStyle::ALL
+        .iter()
+        .map(|style| style.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    // In private chats users pick how they are named everywhere, in groups admins pick the default.
+    let scope = if msg.chat.is_private() {
+        "your name style"
+    } else {
+        "the name style of this chat"
+    };
+
+    let user = msg.from.as_ref().map(|user| user.id);
+    let name = name.trim();
+    if name.is_empty() {
+        let chat_style = settings::chat_name_style(&pool, msg.chat.id).await;
+        let current = match user {
+            Some(user) if msg.chat.is_private() => settings::names(&pool, chat_style, &[user])
+                .await
+                .style_of(user),
+            _ => chat_style,
+        };
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "Current name style is {}. Available styles: {available}.",
+                current.as_str()
+            ),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let Some(style) = NameStyle::from_name(name) else {
+        bot.send_message(
+            msg.chat.id,
+            format!("Unknown name style {name}. Available styles: {available}."),
+        )
+        .await?;
+        return Ok(());
+    };
+
+    if let (true, Some(user)) = (msg.chat.is_private(), user) {
+        sqlx::query!(
+            "INSERT INTO user_name (id, style) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET style = $2",
+            user.0.cast_signed(),
+            style.as_str()
+        )
+        .execute(&pool)
+        .await?;
+    } else {
+        if !check_privileged(&bot, &msg, "set the name style").await? {
+            return Ok(());
+        }
+        sqlx::query!(
+            "INSERT INTO chat_name_style (id, style) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET style = $2",
+            msg.chat.id.0,
+            style.as_str()
+        )
+        .execute(&pool)
+        .await?;
+    }
+    bot.send_message(msg.chat.id, format!("Set {scope} to {}.", style.as_str()))
+        .await?;
+    Ok(())
+}
+
+#[instrument(fields(from = %msg.chat.id, msg = ? msg.text()), skip(msg, bot, pool))]
+pub async fn nickname_handler(
+    msg: Message,
+    bot: Bot,
+    nickname: String,
+    pool: sqlx::PgPool,
+) -> Result<()> {
+    let Some(user) = &msg.from else {
+        return Ok(());
+    };
+
+    let nickname = nickname.trim();
+    if nickname.is_empty() {
+        sqlx::query!(
+            "UPDATE user_name SET nickname = NULL, \
+             style = CASE WHEN style = 'nickname' THEN NULL ELSE style END WHERE id = $1",
+            user.id.0.cast_signed()
+        )
+        .execute(&pool)
+        .await?;
+        bot.send_message(msg.chat.id, "Nickname cleared.").await?;
+        return Ok(());
+    }
+
+    // The nickname ends up in every reply naming this user, so keep it short and on one line.
+    if nickname.chars().count() > MAX_NICKNAME_LENGTH || nickname.chars().any(char::is_control) {
+        bot.send_message(
+            msg.chat.id,
+            format!("Nicknames must be a single line of at most {MAX_NICKNAME_LENGTH} characters."),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    // Picking a nickname also means wanting to be called by it.
+    sqlx::query!(
+        "INSERT INTO user_name (id, style, nickname) VALUES ($1, $2, $3) \
+         ON CONFLICT (id) DO UPDATE SET style = $2, nickname = $3",
+        user.id.0.cast_signed(),
+        NameStyle::Nickname.as_str(),
+        nickname
+    )
+    .execute(&pool)
+    .await?;
+    bot.send_message(msg.chat.id, format!("Nickname set to {nickname}."))
+        .await?;
+    Ok(())
+}
+
+#[instrument(fields(from = %msg.chat.id, msg = ? msg.text()), skip(msg, bot, me, booking))]
+pub async fn undo_handler(
+    msg: Message,
+    bot: Bot,
+    me: Me,
+    option: String,
+    booking: Arc<ReplyBooking>,
+) -> Result<()> {
+    let delete_command = match option.trim() {
+        "" => false,
+        "all" => true,
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                "Usage: reply /undo to my message to delete it, or /undo all to delete the command too.",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let Some(reply) = msg
+        .reply_to_message()
+        .filter(|reply| reply.from.as_ref().map(|user| user.id) == Some(me.id))
+    else {
+        bot.send_message(
+            msg.chat.id,
+            "Reply /undo to one of my messages to delete it.",
+        )
+        .await?;
+        return Ok(());
+    };
+    let Some(command) = booking.reverse_lookup(reply.into()).await else {
+        bot.send_message(
+            msg.chat.id,
+            "I don't know which command this message answers, so it can't be undone.",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    // Only whoever sent the command can take it back, unless an admin steps in.
+    let is_sender = msg.from.as_ref().map(|user| user.id) == Some(command.sender);
+    if !is_sender && !check_privileged(&bot, &msg, "undo someone else's command").await? {
+        return Ok(());
+    }
+
+    if let Err(e) = bot.delete_message(reply.chat.id, reply.id).await {
+        warn!(?e, "failed to delete reply");
+        bot.send_message(msg.chat.id, "This message can't be deleted any more.")
+            .await?;
+        return Ok(());
+    }
+    booking.forget(command.message).await?;
+
+    if delete_command {
+        if let Err(e) = bot
+            .delete_message(command.message.chat_id, command.message.message_id)
+            .await
+        {
+            warn!(?e, "failed to delete command");
+            bot.send_message(
+                msg.chat.id,
+                "The command can't be deleted. I need to be an admin allowed to delete messages.",
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Shows operators how the reply bookings are doing. Everyone else is ignored, as if the command
+/// didn't exist.
+#[instrument(fields(from = %msg.chat.id, msg = ? msg.text()), skip(msg, bot, booking))]
+pub async fn booking_stats_handler(
+    msg: Message,
+    bot: Bot,
+    booking: Arc<ReplyBooking>,
+) -> Result<()> {
+    let operators = OPERATORS.get().unwrap();
+    if !msg
+        .from
+        .as_ref()
+        .is_some_and(|user| operators.contains(&user.id))
+    {
+        return Ok(());
+    }
+    bot.send_message(msg.chat.id, booking.stats().await.to_string())
+        .await?;
+    Ok(())
+}
+
 #[instrument(fields(from = %msg.chat.id, msg = ? msg.text()), skip(msg, bot, booking))]
 pub async fn message_handler(
     msg: Message,
     bot: Bot,
-    booking: Arc<Mutex<ReplyBooking>>,
+    booking: Arc<ReplyBooking>,
     pool: sqlx::PgPool,
 ) -> Result<()> {
     let me = &sentry_capture(bot.get_me().await)?.user;
@@ -88,15 +406,13 @@ pub async fn message_handler(
         .await
         .lift_should_not_handle()?;
 
-    let reply = if msg
-        .text()
-        .expect("must be text message")
-        .starts_with(EXPLAIN_COMMAND)
-    {
-        elaborate(&msg, output)
+    let text = msg.text().expect("must be text message");
+    let reply = if CommandParser::is_explain(text) {
+        elaborate(text, msg.entities().expect("must be text message"), output)
     } else {
-        output.unwrap_or_else(|e| elaborate_error(e).into())
+        output.unwrap_or_else(|e| elaborate_error(text, &e).into())
     };
+    let reply = LIMITS.get().unwrap().apply(reply, "…");
 
     let sent_reply = sentry_capture(
         bot.send_message(msg.chat.id, reply.text())
@@ -106,10 +422,14 @@ pub async fn message_handler(
             .wrap_err("Cannot send reply message"),
     )?;
 
-    booking.lock().book(
-        sentry_capture(msg.try_into())?,
-        sentry_capture(sent_reply.try_into())?,
-    );
+    sentry_capture(
+        booking
+            .book(
+                sentry_capture(booked(&msg))?,
+                sentry_capture(booked(&sent_reply))?,
+            )
+            .await,
+    )?;
 
     Ok(())
 }
@@ -117,46 +437,35 @@ pub async fn message_handler(
 pub async fn edited_message_handler(
     msg: Message,
     bot: Bot,
-    booking: Arc<Mutex<ReplyBooking>>,
+    booking: Arc<ReplyBooking>,
     pool: sqlx::PgPool,
 ) -> Result<()> {
-    let unique_id = sentry_capture(MessageMeta::try_from(&msg))?;
+    let unique_id = MessageKey::from(&msg);
 
     let me = sentry_capture(bot.get_me().await)?.user;
-    let output = process(&me, &booking, &msg, pool).await;
-
-    if matches!(output, Err(Error::ShouldNotHandle)) {
+    let Ok(output) = process(&me, &booking, &msg, pool)
+        .await
+        .lift_should_not_handle()
+    else {
         // this is no longer a valid msg, delete previous reply
-        let reply_id = booking.lock().forward_lookup(&unique_id).cloned();
-        if let Some(reply_id) = reply_id {
-            sentry_capture(
-                bot.delete_message(reply_id.chat_id, reply_id.message_id)
-                    .await
-                    .wrap_err("Cannot delete sent message"),
-            )?;
-            booking.lock().forget(&unique_id);
+        if sentry_capture(delete_reply(&bot, &booking, unique_id).await)? {
+            sentry_capture(booking.forget(unique_id).await)?;
         }
         return Ok(());
-    }
+    };
 
-    let reply = if msg
-        .text()
-        .expect("must be text message")
-        .starts_with(EXPLAIN_COMMAND)
-    {
-        elaborate(&msg, output)
+    let text = msg.text().expect("must be text message");
+    let reply = if CommandParser::is_explain(text) {
+        elaborate(text, msg.entities().expect("must be text message"), output)
     } else {
-        output.unwrap_or_else(|e| elaborate_error(e).into())
+        output.unwrap_or_else(|e| elaborate_error(text, &e).into())
     };
+    let reply = LIMITS.get().unwrap().apply(reply, "…");
 
-    let reply_id = booking.lock().forward_lookup(&unique_id).cloned();
-    let sent_reply = if let Some(reply_id) = reply_id {
-        sentry_capture(
-            bot.edit_message_text(reply_id.chat_id, reply_id.message_id, reply.text())
-                .entities(reply.entities())
-                .await
-                .wrap_err("Cannot edit sent message"),
-        )?
+    let sent_reply = if let Some(sent_reply) =
+        sentry_capture(edit_reply(&bot, &booking, unique_id, &reply).await)?
+    {
+        sent_reply
     } else {
         sentry_capture(
             bot.send_message(msg.chat.id, reply.text())
@@ -167,10 +476,68 @@ pub async fn edited_message_handler(
         )?
     };
 
-    booking.lock().book(
-        sentry_capture(msg.try_into())?,
-        sentry_capture(sent_reply.try_into())?,
-    );
+    sentry_capture(
+        booking
+            .book(
+                sentry_capture(booked(&msg))?,
+                sentry_capture(booked(&sent_reply))?,
+            )
+            .await,
+    )?;
 
     Ok(())
 }
+
+// Other instances of the bot may have replaced or deleted a reply since this one cached it. So if
+// Telegram refuses to touch the cached reply, the database is asked again before giving up.
+
+// Edits the reply to `command`, returning `None` if there is none.
+async fn edit_reply(
+    bot: &Bot,
+    booking: &ReplyBooking,
+    command: MessageKey,
+    reply: &Segments,
+) -> Result<Option<Message>> {
+    let Some(cached) = booking.forward_lookup(command).await else {
+        return Ok(None);
+    };
+    let edit = |booked: Booked| {
+        bot.edit_message_text(
+            booked.message.chat_id,
+            booked.message.message_id,
+            reply.text(),
+        )
+        .entities(reply.entities())
+    };
+    match edit(cached).await {
+        Ok(sent) => Ok(Some(sent)),
+        Err(e) => match booking.forward_lookup_stored(command).await {
+            Some(stored) if stored != cached => Ok(Some(
+                edit(stored).await.wrap_err("Cannot edit sent message")?,
+            )),
+            Some(_) => Err(e).wrap_err("Cannot edit sent message"),
+            None => Ok(None),
+        },
+    }
+}
+
+// Deletes the reply to `command`, returning whether there was one.
+async fn delete_reply(bot: &Bot, booking: &ReplyBooking, command: MessageKey) -> Result<bool> {
+    let Some(cached) = booking.forward_lookup(command).await else {
+        return Ok(false);
+    };
+    let delete =
+        |booked: Booked| bot.delete_message(booked.message.chat_id, booked.message.message_id);
+    if let Err(e) = delete(cached).await {
+        match booking.forward_lookup_stored(command).await {
+            Some(stored) if stored != cached => {
+                delete(stored)
+                    .await
+                    .wrap_err("Cannot delete sent message")?;
+            }
+            Some(_) => return Err(e).wrap_err("Cannot delete sent message"),
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
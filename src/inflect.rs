@@ -0,0 +1,217 @@
+/// How naive mode turns the command into a verb in the past tense.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Inflection {
+    /// Keep the command as is, and rely on the aspect marker instead.
+    None,
+    /// English simple past, e.g. `slap` -> `slapped`.
+    English,
+}
+
+impl Inflection {
+    /// Inflects `verb`, or returns it unchanged if the inflection doesn't apply to it.
+    pub fn apply(self, verb: &str) -> String {
+        match self {
+            Self::None => verb.to_string(),
+            Self::English => english_past(verb),
+        }
+    }
+}
+
+// Sorted by the present form, so that it can be searched.
+const ENGLISH_IRREGULAR: &[(&str, &str)] = &[
+    ("be", "was"),
+    ("bear", "bore"),
+    ("beat", "beat"),
+    ("become", "became"),
+    ("begin", "began"),
+    ("bend", "bent"),
+    ("bind", "bound"),
+    ("bite", "bit"),
+    ("bleed", "bled"),
+    ("blow", "blew"),
+    ("break", "broke"),
+    ("bring", "brought"),
+    ("build", "built"),
+    ("buy", "bought"),
+    ("cast", "cast"),
+    ("catch", "caught"),
+    ("choose", "chose"),
+    ("come", "came"),
+    ("cut", "cut"),
+    ("dig", "dug"),
+    ("do", "did"),
+    ("draw", "drew"),
+    ("drink", "drank"),
+    ("drive", "drove"),
+    ("eat", "ate"),
+    ("fall", "fell"),
+    ("feed", "fed"),
+    ("feel", "felt"),
+    ("fight", "fought"),
+    ("find", "found"),
+    ("fling", "flung"),
+    ("fly", "flew"),
+    ("forget", "forgot"),
+    ("forgive", "forgave"),
+    ("freeze", "froze"),
+    ("get", "got"),
+    ("give", "gave"),
+    ("go", "went"),
+    ("grind", "ground"),
+    ("hang", "hung"),
+    ("have", "had"),
+    ("hear", "heard"),
+    ("hide", "hid"),
+    ("hit", "hit"),
+    ("hold", "held"),
+    ("hurt", "hurt"),
+    ("keep", "kept"),
+    ("kneel", "knelt"),
+    ("know", "knew"),
+    ("lay", "laid"),
+    ("lead", "led"),
+    ("leave", "left"),
+    ("lend", "lent"),
+    ("let", "let"),
+    ("lie", "lay"),
+    ("light", "lit"),
+    ("lose", "lost"),
+    ("make", "made"),
+    ("meet", "met"),
+    ("pay", "paid"),
+    ("put", "put"),
+    ("quit", "quit"),
+    ("read", "read"),
+    ("ride", "rode"),
+    ("ring", "rang"),
+    ("rise", "rose"),
+    ("run", "ran"),
+    ("say", "said"),
+    ("see", "saw"),
+    ("seek", "sought"),
+    ("sell", "sold"),
+    ("send", "sent"),
+    ("set", "set"),
+    ("shake", "shook"),
+    ("shoot", "shot"),
+    ("shrink", "shrank"),
+    ("shut", "shut"),
+    ("sing", "sang"),
+    ("sink", "sank"),
+    ("sit", "sat"),
+    ("slay", "slew"),
+    ("sleep", "slept"),
+    ("slide", "slid"),
+    ("sling", "slung"),
+    ("smite", "smote"),
+    ("speak", "spoke"),
+    ("spend", "spent"),
+    ("spin", "spun"),
+    ("spit", "spat"),
+    ("split", "split"),
+    ("spread", "spread"),
+    ("stand", "stood"),
+    ("steal", "stole"),
+    ("stick", "stuck"),
+    ("sting", "stung"),
+    ("stink", "stank"),
+    ("strike", "struck"),
+    ("swear", "swore"),
+    ("sweep", "swept"),
+    ("swim", "swam"),
+    ("swing", "swung"),
+    ("take", "took"),
+    ("teach", "taught"),
+    ("tear", "tore"),
+    ("tell", "told"),
+    ("think", "thought"),
+    ("throw", "threw"),
+    ("wake", "woke"),
+    ("wear", "wore"),
+    ("weep", "wept"),
+    ("win", "won"),
+    ("wring", "wrung"),
+    ("write", "wrote"),
+];
+
+// Verbs of more than one syllable that are stressed on the last one, and so double the final
+// consonant.
+const ENGLISH_DOUBLING: &[&str] = &[
+    "admit", "commit", "compel", "control", "equip", "kidnap", "occur", "omit", "patrol", "prefer",
+    "propel", "rebel", "refer", "regret", "submit", "transfer", "unwrap",
+];
+
+const fn is_vowel(c: u8) -> bool {
+    matches!(c, b'a' | b'e' | b'i' | b'o' | b'u')
+}
+
+// Positions of the vowels in `word`, not counting the `u` in `qu`.
+fn vowels(word: &[u8]) -> Vec<usize> {
+    (0..word.len())
+        .filter(|&i| is_vowel(word[i]) && !(word[i] == b'u' && i > 0 && word[i - 1] == b'q'))
+        .collect()
+}
+
+// Whether the final consonant is doubled, as in `slap` -> `slapped`.
+fn should_double(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    let vowels = vowels(bytes);
+    let n = bytes.len();
+    // Ends with a single vowel followed by a single consonant.
+    let ends_cvc = n >= 3
+        && !matches!(bytes[n - 1], b'w' | b'x' | b'y')
+        && vowels.last() == Some(&(n - 2))
+        && !vowels.contains(&(n - 3));
+    if !ends_cvc {
+        return false;
+    }
+    // Vowels that are not next to each other belong to different syllables.
+    let syllables = 1 + vowels.windows(2).filter(|w| w[1] != w[0] + 1).count();
+    syllables == 1 || ENGLISH_DOUBLING.contains(&word)
+}
+
+fn regular_past(word: &str) -> String {
+    let bytes = word.as_bytes();
+    match bytes {
+        [.., b'e'] => format!("{word}d"),
+        [.., before, b'y'] if !is_vowel(*before) => format!("{}ied", &word[..word.len() - 1]),
+        [.., b'i', b'c'] => format!("{word}ked"),
+        [.., last] if should_double(word) => format!("{word}{}ed", char::from(*last)),
+        _ => format!("{word}ed"),
+    }
+}
+
+/// Simple past of an English verb, keeping its capitalisation.
+///
+/// Only the trailing run of ASCII letters is inflected, so that `high-five` becomes
+/// `high-fived`. Anything else is returned unchanged.
+pub fn english_past(verb: &str) -> String {
+    let start = verb
+        .rfind(|c: char| !c.is_ascii_alphabetic())
+        .map_or(0, |i| {
+            i + verb[i..].chars().next().map_or(1, char::len_utf8)
+        });
+    let (head, word) = verb.split_at(start);
+    if word.is_empty() {
+        return verb.to_string();
+    }
+
+    let lower = word.to_ascii_lowercase();
+    let past = ENGLISH_IRREGULAR
+        .binary_search_by_key(&lower.as_str(), |(present, _)| present)
+        .map_or_else(
+            |_| regular_past(&lower),
+            |i| ENGLISH_IRREGULAR[i].1.to_string(),
+        );
+
+    let past = if word.len() > 1 && word.bytes().all(|c| c.is_ascii_uppercase()) {
+        past.to_ascii_uppercase()
+    } else if word.starts_with(|c: char| c.is_ascii_uppercase()) {
+        let mut past = past;
+        past[..1].make_ascii_uppercase();
+        past
+    } else {
+        past
+    };
+    format!("{head}{past}")
+}
@@ -0,0 +1,62 @@
+//! The template engine behind the hithit bot.
+//!
+//! A Telegram message (text plus entities) is turned into [`Segments`](segments::Segments),
+//! parsed into a [`Formatter`](formatter::Formatter), and rendered with a
+//! [`FormatContext`](formatter::FormatContext) into rich text that can be sent back as-is.
+//!
+//! ```
+//! use hithit_bot::formatter::FormatContext;
+//! use hithit_bot::parser::Parser;
+//! use hithit_bot::segments::{Segment, Segments};
+//!
+//! let template = Segments::build("{s} 打了 {r}", &[]);
+//! let formatter = Parser::new(template, true)
+//!     .try_as_formatter()?
+//!     .expect("message is a template");
+//!
+//! let ctx = FormatContext::new(
+//!     Segment::plain("Alice"),
+//!     Segment::plain("Bob"),
+//!     Segment::plain("自己"),
+//! );
+//! let output = formatter.format(&ctx)?;
+//!
+//! assert_eq!(output.text(), "Alice 打了 Bob！");
+//! assert!(output.entities().is_empty());
+//! # Ok::<(), hithit_bot::error::ExportedError>(())
+//! ```
+#![allow(
+    clippy::non_ascii_literal,
+    clippy::wildcard_imports,
+    clippy::module_name_repetitions,
+    clippy::useless_transmute,
+    clippy::default_trait_access
+)]
+
+/// Remembering which reply of the bot answers which command.
+#[cfg(feature = "bot")]
+pub mod booking;
+/// Recognising hit commands in incoming messages.
+pub mod command;
+/// Human readable explanations of a render, as used by `/explain`.
+pub mod elaborator;
+/// Errors reported by the template engine.
+pub mod error;
+/// Parsed templates and how they are rendered.
+pub mod formatter;
+/// Verb inflection for naive mode.
+pub mod inflect;
+/// Making replies fit in a Telegram message.
+pub mod limits;
+/// Language-specific words used while rendering.
+pub mod locale;
+/// Telegram's HTML and MarkdownV2 markup for rich text.
+pub mod markup;
+/// Positions in text, in the units Telegram and users count in.
+pub mod offset;
+/// Parsing messages into templates.
+pub mod parser;
+/// Rich text, and conversion from and to Telegram entities.
+pub mod segments;
+/// Timezones as chats set them with `/timezone`.
+pub mod time;
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use teloxide::types::{MessageEntityKind, UserId};
+
+use crate::offset::Utf16Offset;
+use crate::segments::{Segment, Segments};
+
+/// Limits a reply has to fit in: Telegram's, and how many times one user may be pinged.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Limits {
+    /// Length in UTF-16 code units.
+    pub max_length: usize,
+    pub max_entities: usize,
+    /// How many times a single user may be mentioned. Further mentions keep the name but don't
+    /// ping.
+    pub max_pings: usize,
+}
+
+impl Default for Limits {
+    /// Telegram's limits, with no cap on pings.
+    fn default() -> Self {
+        Self {
+            max_length: 4096,
+            max_entities: 100,
+            max_pings: usize::MAX,
+        }
+    }
+}
+
+#[derive(Hash, Eq, PartialEq)]
+enum Pinged {
+    User(UserId),
+    Username(String),
+}
+
+impl Limits {
+    /// How many times the bot pings a single user in a reply, unless configured otherwise.
+    pub const DEFAULT_MAX_PINGS: usize = 3;
+    /// How many characters of the replied message `{quote}` keeps, unless configured otherwise.
+    pub const DEFAULT_QUOTE_LIMIT: usize = 100;
+
+    #[must_use]
+    pub const fn with_max_pings(mut self, max_pings: usize) -> Self {
+        self.max_pings = max_pings;
+        self
+    }
+
+    /// Makes `segments` fit, cutting it off with `marker` if it's too long or has too many
+    /// entities.
+    pub fn apply(&self, segments: Segments, marker: &str) -> Segments {
+        let segments = self.limit_pings(segments.normalize());
+        let segments = self.limit_entities(segments, marker);
+        let marker_length = marker.encode_utf16().count();
+        if segments.utf16_len() > self.max_length {
+            let length = Utf16Offset(self.max_length.saturating_sub(marker_length));
+            segments.truncate_to(length, marker)
+        } else {
+            segments
+        }
+    }
+
+    fn limit_pings(&self, mut segments: Segments) -> Segments {
+        let mention = |segment: &Segment| {
+            segment.kind.iter().find_map(|kind| match kind {
+                MessageEntityKind::TextMention { .. } | MessageEntityKind::Mention => {
+                    Some(kind.clone())
+                }
+                _ => None,
+            })
+        };
+
+        let mut pings: HashMap<Pinged, usize> = HashMap::new();
+        let mut start = 0;
+        while start < segments.len() {
+            let Some(kind) = mention(&segments[start]) else {
+                start += 1;
+                continue;
+            };
+            // Formatting may split one mention over several segments, which still ping once.
+            let end = (start + 1..segments.len())
+                .find(|&i| mention(&segments[i]).as_ref() != Some(&kind))
+                .unwrap_or(segments.len());
+            let pinged = match kind {
+                MessageEntityKind::TextMention { user } => Pinged::User(user.id),
+                _ => Pinged::Username(
+                    segments
+                        .range(start..end)
+                        .map(|segment| segment.text.to_lowercase())
+                        .collect(),
+                ),
+            };
+            let count = pings.entry(pinged).or_default();
+            *count += 1;
+            if *count > self.max_pings {
+                for segment in segments.range_mut(start..end) {
+                    segment.kind.retain(|kind| {
+                        !matches!(
+                            kind,
+                            MessageEntityKind::TextMention { .. } | MessageEntityKind::Mention
+                        )
+                    });
+                }
+            }
+            start = end;
+        }
+        segments
+    }
+
+    fn limit_entities(&self, segments: Segments, marker: &str) -> Segments {
+        if segments.entities().len() <= self.max_entities {
+            return segments;
+        }
+        // Entities only grow as segments are added, so search for the longest prefix that fits.
+        let prefix = |len: usize| Segments::from(segments.iter().take(len).cloned());
+        let (mut fits, mut too_many) = (0, segments.len());
+        while too_many - fits > 1 {
+            let mid = fits + (too_many - fits) / 2;
+            if prefix(mid).entities().len() <= self.max_entities {
+                fits = mid;
+            } else {
+                too_many = mid;
+            }
+        }
+        let mut truncated = prefix(fits);
+        truncated.push_back(Segment::plain(marker));
+        truncated
+    }
+}
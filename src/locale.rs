@@ -0,0 +1,153 @@
+use crate::inflect::Inflection;
+use crate::offset::CharOffset;
+
+/// Grammar words the renderer inserts on its own, so that chats can use the bot in their own
+/// language.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Locale {
+    pub name: &'static str,
+    /// How the sender refers to themselves, e.g. `自己`.
+    pub reflexive: &'static str,
+    /// How the verb is put into the past tense in naive mode.
+    pub inflection: Inflection,
+    /// Appended to the verb in naive mode, e.g. `了`.
+    pub aspect_marker: &'static str,
+    /// Endings after which the verb needs no aspect marker, e.g. `过` in `吃过`.
+    pub aspects: &'static [&'static str],
+    /// Sentence-final particles the aspect marker goes in front of, e.g. `啊` in `打啊`.
+    pub particles: &'static [&'static str],
+    /// Whether reduplicated verbs take the marker in the middle, e.g. `摸摸` -> `摸了摸`.
+    pub reduplication: bool,
+    /// Appended to the output unless it already ends with one of `termination_marks`.
+    pub exclamation: &'static str,
+    pub termination_marks: &'static [char],
+    /// Between receivers in `{receivers}`, except the last two, e.g. `、`.
+    pub separator: &'static str,
+    /// Between the last two receivers in `{receivers}`, e.g. ` 和 `.
+    pub conjunction: &'static str,
+}
+
+const CJK_MARKS: &[char] = &['。', '，', '！', '？', '；', '、', '.', ',', '!', '?', ';'];
+const LATIN_MARKS: &[char] = &['.', ',', '!', '?', ';', '…'];
+
+impl Locale {
+    pub const ZH_HANS: Self = Self {
+        name: "zh-hans",
+        reflexive: "自己",
+        inflection: Inflection::None,
+        aspect_marker: "了",
+        aspects: &["了", "过", "過", "着", "著"],
+        particles: &[
+            "啊", "呀", "吧", "呢", "哦", "喔", "嘛", "啦", "哈", "呐", "哇", "耶",
+        ],
+        reduplication: true,
+        exclamation: "！",
+        termination_marks: CJK_MARKS,
+        separator: "、",
+        conjunction: " 和 ",
+    };
+    pub const YUE: Self = Self {
+        name: "yue",
+        reflexive: "自己",
+        inflection: Inflection::None,
+        aspect_marker: "咗",
+        aspects: &["咗", "过", "過", "紧", "緊"],
+        particles: &[
+            "啊", "呀", "吖", "啦", "喇", "嘞", "囉", "咯", "喎", "嘅", "呢", "㗎",
+        ],
+        reduplication: true,
+        exclamation: "！",
+        termination_marks: CJK_MARKS,
+        separator: "、",
+        conjunction: " 同 ",
+    };
+    pub const JA: Self = Self {
+        name: "ja",
+        reflexive: "自分",
+        inflection: Inflection::None,
+        aspect_marker: "",
+        aspects: &[],
+        particles: &[],
+        reduplication: false,
+        exclamation: "！",
+        termination_marks: CJK_MARKS,
+        separator: "、",
+        conjunction: "と",
+    };
+    pub const EN: Self = Self {
+        name: "en",
+        reflexive: "themselves",
+        inflection: Inflection::English,
+        aspect_marker: "",
+        aspects: &[],
+        particles: &[],
+        reduplication: false,
+        exclamation: "!",
+        termination_marks: LATIN_MARKS,
+        separator: ", ",
+        conjunction: " and ",
+    };
+
+    pub const ALL: &'static [Self] = &[Self::ZH_HANS, Self::YUE, Self::JA, Self::EN];
+
+    /// Where naive mode puts the aspect marker into `verb`, or `None` if it needs no marker.
+    ///
+    /// The marker goes after the verb but in front of trailing particles, emoji and
+    /// punctuation, e.g. `打啊🥺` -> `打了啊🥺`.
+    pub fn aspect_position(&self, verb: &str) -> Option<CharOffset> {
+        if self.aspect_marker.is_empty() {
+            return None;
+        }
+
+        let chars: Vec<char> = verb.chars().collect();
+        let mut end = chars.len();
+        loop {
+            let stem = String::from_iter(&chars[..end]);
+            if chars[..end].last().is_some_and(|c| !c.is_alphanumeric()) {
+                end -= 1;
+            } else if let Some(particle) = self
+                .particles
+                .iter()
+                .find(|particle| stem.len() > particle.len() && stem.ends_with(*particle))
+            {
+                end -= particle.chars().count();
+            } else {
+                break;
+            }
+        }
+        if end == 0 {
+            // Nothing but emoji and punctuation, so treat all of it as the verb.
+            end = chars.len();
+        }
+
+        let stem = &chars[..end];
+        let stem_text = String::from_iter(stem);
+        if stem_text.ends_with(self.aspect_marker)
+            || self
+                .aspects
+                .iter()
+                .any(|aspect| stem_text.ends_with(aspect))
+        {
+            return None;
+        }
+        match stem {
+            [a, b, ..] | [a, '一', b, ..] if self.reduplication && a == b && a.is_alphabetic() => {
+                Some(CharOffset(1))
+            }
+            _ => Some(CharOffset(end)),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|locale| locale.name.eq_ignore_ascii_case(name))
+            .copied()
+    }
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self::ZH_HANS
+    }
+}
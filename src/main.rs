@@ -6,14 +6,15 @@
     clippy::default_trait_access
 )]
 
+use std::collections::HashSet;
 use std::env;
 use std::fmt::Debug;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures_core::future::BoxFuture;
 use once_cell::sync::OnceCell;
-use parking_lot::Mutex;
 use sentry::integrations::tracing::EventFilter;
 use sentry::{ClientOptions, IntoDsn};
 use sqlx::migrate::Migrator;
@@ -23,7 +24,7 @@ use teloxide::dptree::case;
 use teloxide::error_handlers::ErrorHandler;
 use teloxide::macros::BotCommands;
 use teloxide::requests::Requester;
-use teloxide::types::{Message, Update};
+use teloxide::types::{Message, Update, UserId};
 use teloxide::update_listeners;
 use teloxide::utils::command::BotCommands as _;
 use teloxide::{dptree, Bot};
@@ -31,23 +32,29 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Layer};
 
-use crate::handlers::{compatibility_handler, edited_message_handler, message_handler};
+use hithit_bot::command::CommandParser;
+use hithit_bot::limits::Limits;
+
+use crate::handlers::{
+    booking_stats_handler, compatibility_handler, edited_message_handler, locale_handler,
+    message_handler, name_style_handler, nickname_handler, timezone_handler, undo_handler,
+};
 use crate::memory::ReplyBooking;
 
 mod axum_listener;
-mod elaborator;
-mod error;
-mod formatter;
 mod handlers;
 mod memory;
-mod parser;
 mod process;
-mod segments;
+mod settings;
+mod users;
 mod utils;
 
-const EXPLAIN_COMMAND: &str = "/explain";
-static EXPLAIN_COMMAND_EXTENDED: OnceCell<String> = OnceCell::new();
-static COMMAND_PREFIX: OnceCell<char> = OnceCell::new();
+static COMMANDS: OnceCell<CommandParser> = OnceCell::new();
+/// How many characters of the replied message `{quote}` keeps.
+static QUOTE_LIMIT: OnceCell<usize> = OnceCell::new();
+static LIMITS: OnceCell<Limits> = OnceCell::new();
+/// Users allowed to run the hidden debugging commands.
+static OPERATORS: OnceCell<HashSet<UserId>> = OnceCell::new();
 
 static MIGRATOR: Migrator = sqlx::migrate!();
 
@@ -55,17 +62,40 @@ static MIGRATOR: Migrator = sqlx::migrate!();
 async fn main() {
     let _ = dotenvy::dotenv();
 
-    COMMAND_PREFIX
+    let prefix = env::var("HITHIT_BOT_PREFIX")
+        .unwrap_or_else(|_| {
+            option_env!("HITHIT_BOT_PREFIX_BUILD")
+                .unwrap_or("^")
+                .to_string()
+        })
+        .chars()
+        .next()
+        .unwrap_or('^');
+    QUOTE_LIMIT
+        .set(
+            env::var("HITHIT_QUOTE_LIMIT")
+                .ok()
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(Limits::DEFAULT_QUOTE_LIMIT),
+        )
+        .unwrap();
+    LIMITS
+        .set(
+            Limits::default().with_max_pings(
+                env::var("HITHIT_MAX_PINGS")
+                    .ok()
+                    .and_then(|limit| limit.parse().ok())
+                    .unwrap_or(Limits::DEFAULT_MAX_PINGS),
+            ),
+        )
+        .unwrap();
+    OPERATORS
         .set(
-            env::var("HITHIT_BOT_PREFIX")
-                .unwrap_or_else(|_| {
-                    option_env!("HITHIT_BOT_PREFIX_BUILD")
-                        .unwrap_or("^")
-                        .to_string()
-                })
-                .chars()
-                .next()
-                .unwrap_or('^'),
+            env::var("HITHIT_OPERATORS")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|id| id.trim().parse().ok().map(UserId))
+                .collect(),
         )
         .unwrap();
 
@@ -100,12 +130,10 @@ async fn main() {
 
     let bot_info = bot.get_me().await.expect("Unable to get bot info.");
     let bot_name = bot_info.username();
-    EXPLAIN_COMMAND_EXTENDED
-        .set(format!("{EXPLAIN_COMMAND}@{bot_name}"))
+    COMMANDS
+        .set(CommandParser::new(prefix).with_bot_username(bot_name))
         .unwrap();
 
-    let booking = Arc::new(Mutex::new(ReplyBooking::with_capacity(8192)));
-
     let pg_opts =
         PgConnectOptions::from_str(&env::var("DATABASE_URL").expect("DATABASE_URL must be set"))
             .expect("DATABASE_URL must be a valid PG connection string");
@@ -123,6 +151,28 @@ async fn main() {
         .await
         .expect("Failed to run migrations");
 
+    let booking = Arc::new(ReplyBooking::new(
+        pgpool.clone(),
+        env::var("HITHIT_BOOKING_CAPACITY")
+            .ok()
+            .and_then(|capacity| capacity.parse().ok())
+            .unwrap_or(8192),
+        env::var("HITHIT_BOOKING_MAX_AGE")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map_or(ReplyBooking::DEFAULT_MAX_AGE, Duration::from_secs),
+    ));
+    tokio::spawn({
+        let booking = booking.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                booking.prune().await;
+            }
+        }
+    });
+
     let command_handler = teloxide::filter_command::<Command, _>()
         .branch(
             case![Command::Help].endpoint(|msg: Message, bot: Bot| async move {
@@ -137,10 +187,17 @@ async fn main() {
                 Ok(())
             }),
         )
-        .branch(case![Command::Compatibility(mode)].endpoint(compatibility_handler));
+        .branch(case![Command::Compatibility(mode)].endpoint(compatibility_handler))
+        .branch(case![Command::Locale(name)].endpoint(locale_handler))
+        .branch(case![Command::Timezone(offset)].endpoint(timezone_handler))
+        .branch(case![Command::NameStyle(name)].endpoint(name_style_handler))
+        .branch(case![Command::Nickname(nickname)].endpoint(nickname_handler))
+        .branch(case![Command::Undo(option)].endpoint(undo_handler))
+        .branch(case![Command::BookingStats].endpoint(booking_stats_handler));
     let mut dp = Dispatcher::builder(
         bot.clone(),
         dptree::entry()
+            .inspect(users::record_update)
             .branch(Update::filter_message().branch(command_handler).branch(
                 dptree::filter(|msg: Message| msg.text().is_some()).endpoint(message_handler),
             ))
@@ -195,6 +252,22 @@ enum Command {
     Help,
     #[command(description = "set compatibility mode. <true/false>")]
     Compatibility(bool),
+    #[command(description = "show or set the language of rendered messages. <zh-hans/yue/ja/en>")]
+    Locale(String),
+    #[command(description = "show or set the timezone of {time} and {date}. <+08:00>")]
+    Timezone(String),
+    #[command(
+        description = "show or set how users are named; in private chats, how you are named. <first/full/username/nickname>"
+    )]
+    NameStyle(String),
+    #[command(description = "set your nickname, or clear it if empty. <nickname>")]
+    Nickname(String),
+    #[command(
+        description = "reply to one of my messages to delete it; with all, delete the command too. <all>"
+    )]
+    Undo(String),
+    #[command(hide)]
+    BookingStats,
 }
 
 struct TracingErrorHandler;
@@ -0,0 +1,816 @@
+use teloxide::types::{MessageEntity, MessageEntityKind, User, UserId};
+use thiserror::Error;
+
+use crate::segments::Segments;
+
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub enum MarkupErrorKind {
+    #[error("unknown tag `<{0}>`")]
+    UnknownTag(String),
+    #[error("`<{tag}>` without `{attribute}`")]
+    MissingAttribute {
+        tag: String,
+        attribute: &'static str,
+    },
+    #[error("unknown character reference `&{0};`")]
+    UnknownReference(String),
+    #[error("invalid URL `{0}`")]
+    InvalidUrl(String),
+    #[error("unmatched `{0}`")]
+    Unmatched(String),
+    #[error("unclosed `{0}`")]
+    Unclosed(String),
+    #[error("`{0}` must be escaped")]
+    Unescaped(char),
+}
+
+/// An error in HTML or MarkdownV2 markup, located in chars of the markup.
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+#[error("{kind} at position {position}")]
+pub struct MarkupError {
+    pub kind: MarkupErrorKind,
+    pub position: usize,
+}
+
+impl MarkupError {
+    const fn new(kind: MarkupErrorKind, position: usize) -> Self {
+        Self { kind, position }
+    }
+}
+
+type Result<T, E = MarkupError> = std::result::Result<T, E>;
+
+const USER_URL: &str = "tg://user?id=";
+const EMOJI_URL: &str = "tg://emoji?id=";
+
+/// Characters that have to be escaped in MarkdownV2 text.
+const MARKDOWN_V2_RESERVED: &[char] = &[
+    '_', '*', '[', ']', '(', ')', '~', '`', '>', '#', '+', '-', '=', '|', '{', '}', '.', '!', '\\',
+];
+
+// The order in which entities starting at the same place are opened, outermost first. Telegram
+// doesn't allow anything inside code, and blockquotes have to be at the top level.
+//
+// Entities Telegram detects from the text by itself, such as `@username` mentions or URLs, have
+// no markup and are written as plain text.
+const fn rank(kind: &MessageEntityKind) -> Option<u8> {
+    Some(match kind {
+        MessageEntityKind::Blockquote => 0,
+        MessageEntityKind::TextLink { .. } => 1,
+        MessageEntityKind::TextMention { .. } => 2,
+        MessageEntityKind::Bold => 3,
+        MessageEntityKind::Italic => 4,
+        MessageEntityKind::Underline => 5,
+        MessageEntityKind::Strikethrough => 6,
+        MessageEntityKind::Spoiler => 7,
+        MessageEntityKind::CustomEmoji { .. } => 8,
+        MessageEntityKind::Pre { .. } => 9,
+        MessageEntityKind::Code => 10,
+        _ => return None,
+    })
+}
+
+const fn is_code(kind: &MessageEntityKind) -> bool {
+    matches!(
+        kind,
+        MessageEntityKind::Code | MessageEntityKind::Pre { .. }
+    )
+}
+
+/// A markup language Telegram can parse.
+trait Markup {
+    fn open(kind: &MessageEntityKind, out: &mut String);
+    fn close(kind: &MessageEntityKind, out: &mut String);
+    /// Writes `text`, escaped as needed inside the `open` entities.
+    fn text(text: &str, open: &[MessageEntityKind], out: &mut String);
+}
+
+struct Html;
+
+fn escape_html(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+impl Markup for Html {
+    fn open(kind: &MessageEntityKind, out: &mut String) {
+        match kind {
+            MessageEntityKind::Bold => out.push_str("<b>"),
+            MessageEntityKind::Italic => out.push_str("<i>"),
+            MessageEntityKind::Underline => out.push_str("<u>"),
+            MessageEntityKind::Strikethrough => out.push_str("<s>"),
+            MessageEntityKind::Spoiler => out.push_str("<tg-spoiler>"),
+            MessageEntityKind::Code => out.push_str("<code>"),
+            MessageEntityKind::Pre { language: None } => out.push_str("<pre>"),
+            MessageEntityKind::Pre {
+                language: Some(language),
+            } => {
+                out.push_str("<pre><code class=\"language-");
+                escape_html(language, out);
+                out.push_str("\">");
+            }
+            MessageEntityKind::TextLink { url } => {
+                out.push_str("<a href=\"");
+                escape_html(url.as_str(), out);
+                out.push_str("\">");
+            }
+            MessageEntityKind::TextMention { user } => {
+                out.push_str(&format!("<a href=\"{USER_URL}{}\">", user.id));
+            }
+            MessageEntityKind::CustomEmoji { custom_emoji_id } => {
+                out.push_str("<tg-emoji emoji-id=\"");
+                escape_html(custom_emoji_id, out);
+                out.push_str("\">");
+            }
+            MessageEntityKind::Blockquote => out.push_str("<blockquote>"),
+            _ => {}
+        }
+    }
+
+    fn close(kind: &MessageEntityKind, out: &mut String) {
+        out.push_str(match kind {
+            MessageEntityKind::Bold => "</b>",
+            MessageEntityKind::Italic => "</i>",
+            MessageEntityKind::Underline => "</u>",
+            MessageEntityKind::Strikethrough => "</s>",
+            MessageEntityKind::Spoiler => "</tg-spoiler>",
+            MessageEntityKind::Code => "</code>",
+            MessageEntityKind::Pre { language: None } => "</pre>",
+            MessageEntityKind::Pre { language: Some(_) } => "</code></pre>",
+            MessageEntityKind::TextLink { .. } | MessageEntityKind::TextMention { .. } => "</a>",
+            MessageEntityKind::CustomEmoji { .. } => "</tg-emoji>",
+            MessageEntityKind::Blockquote => "</blockquote>",
+            _ => "",
+        });
+    }
+
+    fn text(text: &str, _open: &[MessageEntityKind], out: &mut String) {
+        escape_html(text, out);
+    }
+}
+
+struct MarkdownV2;
+
+impl MarkdownV2 {
+    fn marker(marker: &str, out: &mut String) {
+        // `___` is ambiguous, so adjacent underscores are kept apart with a `\r`, which Telegram
+        // ignores.
+        if marker.starts_with('_') && out.ends_with('_') {
+            out.push('\r');
+        }
+        out.push_str(marker);
+    }
+
+    fn url(url: &str, out: &mut String) {
+        out.push_str("](");
+        for c in url.chars() {
+            if matches!(c, ')' | '\\') {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+        out.push(')');
+    }
+}
+
+impl Markup for MarkdownV2 {
+    fn open(kind: &MessageEntityKind, out: &mut String) {
+        match kind {
+            MessageEntityKind::Bold => Self::marker("*", out),
+            MessageEntityKind::Italic => Self::marker("_", out),
+            MessageEntityKind::Underline => Self::marker("__", out),
+            MessageEntityKind::Strikethrough => Self::marker("~", out),
+            MessageEntityKind::Spoiler => Self::marker("||", out),
+            MessageEntityKind::Code => Self::marker("`", out),
+            MessageEntityKind::Pre { language } => {
+                out.push_str("```");
+                out.push_str(language.as_deref().unwrap_or_default());
+                out.push('\n');
+            }
+            MessageEntityKind::TextLink { .. } | MessageEntityKind::TextMention { .. } => {
+                out.push('[');
+            }
+            MessageEntityKind::CustomEmoji { .. } => out.push_str("!["),
+            MessageEntityKind::Blockquote => out.push('>'),
+            _ => {}
+        }
+    }
+
+    fn close(kind: &MessageEntityKind, out: &mut String) {
+        match kind {
+            MessageEntityKind::Bold => Self::marker("*", out),
+            MessageEntityKind::Italic => Self::marker("_", out),
+            MessageEntityKind::Underline => Self::marker("__", out),
+            MessageEntityKind::Strikethrough => Self::marker("~", out),
+            MessageEntityKind::Spoiler => Self::marker("||", out),
+            MessageEntityKind::Code => Self::marker("`", out),
+            MessageEntityKind::Pre { .. } => out.push_str("```"),
+            MessageEntityKind::TextLink { url } => Self::url(url.as_str(), out),
+            MessageEntityKind::TextMention { user } => {
+                Self::url(&format!("{USER_URL}{}", user.id), out);
+            }
+            MessageEntityKind::CustomEmoji { custom_emoji_id } => {
+                Self::url(&format!("{EMOJI_URL}{custom_emoji_id}"), out);
+            }
+            // A blockquote ends with its last line.
+            _ => {}
+        }
+    }
+
+    fn text(text: &str, open: &[MessageEntityKind], out: &mut String) {
+        let in_code = open.iter().any(is_code);
+        let in_quote = open.contains(&MessageEntityKind::Blockquote);
+        for c in text.chars() {
+            let escaped = if in_code {
+                matches!(c, '`' | '\\')
+            } else {
+                MARKDOWN_V2_RESERVED.contains(&c)
+            };
+            if escaped {
+                out.push('\\');
+            }
+            out.push(c);
+            if c == '\n' && in_quote {
+                out.push('>');
+            }
+        }
+    }
+}
+
+fn export<M: Markup>(segments: &Segments) -> String {
+    let mut out = String::new();
+    let mut open: Vec<MessageEntityKind> = vec![];
+    for segment in segments.iter().filter(|segment| !segment.text.is_empty()) {
+        // Keep the entities that continue into this segment, as long as nothing opened inside
+        // them has to be closed first.
+        let kept = open
+            .iter()
+            .take_while(|kind| segment.kind.contains(kind))
+            .count();
+        while open.len() > kept {
+            M::close(&open.pop().unwrap(), &mut out);
+        }
+
+        let mut opening: Vec<_> = segment
+            .kind
+            .iter()
+            .filter(|kind| rank(kind).is_some() && !open.contains(kind))
+            .cloned()
+            .collect();
+        opening.sort_by_key(rank);
+        for kind in opening {
+            M::open(&kind, &mut out);
+            open.push(kind);
+        }
+
+        M::text(&segment.text, &open, &mut out);
+    }
+    while let Some(kind) = open.pop() {
+        M::close(&kind, &mut out);
+    }
+    out
+}
+
+/// An entity that has been opened but not closed yet.
+struct Open {
+    /// The tag or marker that opened it.
+    name: String,
+    /// `None` for the `<code>` holding the language of a `<pre>`, and for links whose entity
+    /// depends on their text.
+    kind: Option<MessageEntityKind>,
+    /// The target of an HTML link that mentions a user.
+    href: Option<String>,
+    /// Where the entity starts in the text, in UTF-16 code units.
+    start: usize,
+    /// Where the entity starts in the text, in bytes.
+    start_byte: usize,
+    /// Where it was opened in the markup, in chars.
+    position: usize,
+}
+
+/// Text and entities, as read from markup.
+#[derive(Default)]
+struct Builder {
+    text: String,
+    utf16_len: usize,
+    entities: Vec<MessageEntity>,
+    open: Vec<Open>,
+}
+
+impl Builder {
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        self.utf16_len += c.len_utf16();
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.text.is_empty() || self.text.ends_with('\n')
+    }
+
+    fn top(&self) -> Option<&str> {
+        self.open.last().map(|open| open.name.as_str())
+    }
+
+    fn open(&mut self, name: impl Into<String>, kind: Option<MessageEntityKind>, position: usize) {
+        self.open.push(Open {
+            name: name.into(),
+            kind,
+            href: None,
+            start: self.utf16_len,
+            start_byte: self.text.len(),
+            position,
+        });
+    }
+
+    /// Closes the innermost entity, which has to be `name`.
+    fn close(&mut self, name: &str, position: usize) -> Result<Open> {
+        match self.open.pop() {
+            Some(open) if open.name == name => Ok(open),
+            Some(open) => Err(MarkupError::new(
+                MarkupErrorKind::Unclosed(open.name),
+                open.position,
+            )),
+            None => Err(MarkupError::new(
+                MarkupErrorKind::Unmatched(name.to_string()),
+                position,
+            )),
+        }
+    }
+
+    fn add(&mut self, kind: MessageEntityKind, start: usize) {
+        // Telegram drops empty entities anyway.
+        if self.utf16_len > start {
+            self.entities.push(MessageEntity {
+                kind,
+                offset: start,
+                length: self.utf16_len - start,
+            });
+        }
+    }
+
+    fn finish(self, unclosed: impl Fn(&str) -> String) -> Result<Segments> {
+        if let Some(open) = self.open.last() {
+            return Err(MarkupError::new(
+                MarkupErrorKind::Unclosed(unclosed(&open.name)),
+                open.position,
+            ));
+        }
+        Ok(Segments::build(&self.text, &self.entities).normalize())
+    }
+}
+
+/// The entity for a link to `url` around `text`.
+fn link(url: &str, text: &str) -> Option<MessageEntityKind> {
+    if let Some(id) = url.strip_prefix(USER_URL) {
+        // Only the id is in the link, so the user is named after the text.
+        return id.parse().ok().map(|id| MessageEntityKind::TextMention {
+            user: User {
+                id: UserId(id),
+                is_bot: false,
+                first_name: text.to_string(),
+                last_name: None,
+                username: None,
+                language_code: None,
+                is_premium: false,
+                added_to_attachment_menu: false,
+            },
+        });
+    }
+    url.parse()
+        .ok()
+        .map(|url| MessageEntityKind::TextLink { url })
+}
+
+fn custom_emoji(url: &str) -> Option<MessageEntityKind> {
+    url.strip_prefix(EMOJI_URL)
+        .filter(|id| !id.is_empty() && id.bytes().all(|c| c.is_ascii_digit()))
+        .map(|id| MessageEntityKind::CustomEmoji {
+            custom_emoji_id: id.to_string(),
+        })
+}
+
+fn find(chars: &[char], from: usize, c: char) -> Option<usize> {
+    chars[from..].iter().position(|&d| d == c).map(|i| from + i)
+}
+
+/// Decodes a character reference such as `amp` or `#x1F600`, without the `&` and `;`.
+fn html_reference(name: &str) -> Option<char> {
+    match name {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "amp" => Some('&'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ => {
+            let code = name.strip_prefix('#')?;
+            let code = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => code.parse(),
+            };
+            code.ok().and_then(char::from_u32)
+        }
+    }
+}
+
+/// Reads the character reference starting at `chars[at]`, returning it and where it ends.
+fn read_html_reference(chars: &[char], at: usize) -> Result<(char, usize)> {
+    // The longest reference is `&#x10FFFF;`.
+    let end = find(&chars[..chars.len().min(at + 10)], at, ';')
+        .ok_or_else(|| MarkupError::new(MarkupErrorKind::Unescaped('&'), at))?;
+    let name: String = chars[at + 1..end].iter().collect();
+    html_reference(&name)
+        .map(|c| (c, end + 1))
+        .ok_or_else(|| MarkupError::new(MarkupErrorKind::UnknownReference(name), at))
+}
+
+/// Splits the inside of a start tag into its name and attributes.
+fn parse_tag(tag: &[char], position: usize) -> Result<(String, Vec<(String, String)>)> {
+    let is_delimiter = |c: char| c.is_whitespace() || c == '=' || c == '/';
+    let name_end = tag
+        .iter()
+        .position(|&c| c.is_whitespace() || c == '/')
+        .unwrap_or(tag.len());
+    let name: String = tag[..name_end].iter().collect();
+
+    let mut attributes = vec![];
+    let mut i = name_end;
+    while i < tag.len() {
+        if is_delimiter(tag[i]) {
+            i += 1;
+            continue;
+        }
+        let key_end = tag[i..]
+            .iter()
+            .position(|&c| is_delimiter(c))
+            .map_or(tag.len(), |n| i + n);
+        let key: String = tag[i..key_end].iter().collect();
+        i = key_end;
+        while i < tag.len() && tag[i].is_whitespace() {
+            i += 1;
+        }
+        let mut value = String::new();
+        if i < tag.len() && tag[i] == '=' {
+            i += 1;
+            while i < tag.len() && tag[i].is_whitespace() {
+                i += 1;
+            }
+            let (start, end) = match tag.get(i) {
+                Some(&quote @ ('"' | '\'')) => {
+                    let end = find(tag, i + 1, quote).ok_or_else(|| {
+                        MarkupError::new(
+                            MarkupErrorKind::Unclosed(quote.to_string()),
+                            position + 1 + i,
+                        )
+                    })?;
+                    (i + 1, end)
+                }
+                _ => {
+                    let end = tag[i..]
+                        .iter()
+                        .position(|c| c.is_whitespace())
+                        .map_or(tag.len(), |n| i + n);
+                    (i, end)
+                }
+            };
+            let mut j = start;
+            while j < end {
+                if tag[j] == '&' {
+                    let (c, next) = read_html_reference(&tag[..end], j)
+                        .map_err(|e| MarkupError::new(e.kind, position + 1 + e.position))?;
+                    value.push(c);
+                    j = next;
+                } else {
+                    value.push(tag[j]);
+                    j += 1;
+                }
+            }
+            i = end + 1;
+        }
+        attributes.push((key.to_ascii_lowercase(), value));
+    }
+    Ok((name.to_ascii_lowercase(), attributes))
+}
+
+fn attribute<'a>(attributes: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.as_str())
+}
+
+impl Builder {
+    fn open_html(&mut self, tag: &[char], position: usize) -> Result<()> {
+        let (name, attributes) = parse_tag(tag, position)?;
+        let required = |attribute_name: &'static str| {
+            attribute(&attributes, attribute_name).ok_or_else(|| {
+                MarkupError::new(
+                    MarkupErrorKind::MissingAttribute {
+                        tag: name.clone(),
+                        attribute: attribute_name,
+                    },
+                    position,
+                )
+            })
+        };
+        let kind = match name.as_str() {
+            "b" | "strong" => MessageEntityKind::Bold,
+            "i" | "em" => MessageEntityKind::Italic,
+            "u" | "ins" => MessageEntityKind::Underline,
+            "s" | "strike" | "del" => MessageEntityKind::Strikethrough,
+            "tg-spoiler" => MessageEntityKind::Spoiler,
+            "span" if attribute(&attributes, "class") == Some("tg-spoiler") => {
+                MessageEntityKind::Spoiler
+            }
+            "blockquote" => MessageEntityKind::Blockquote,
+            "code" => {
+                let language = attribute(&attributes, "class")
+                    .and_then(|class| class.strip_prefix("language-"));
+                let pre = self
+                    .open
+                    .last_mut()
+                    .filter(|open| open.name == "pre" && open.start_byte == self.text.len());
+                if let (Some(language), Some(pre)) = (language, pre) {
+                    pre.kind = Some(MessageEntityKind::Pre {
+                        language: Some(language.to_string()),
+                    });
+                    self.open(name, None, position);
+                    return Ok(());
+                }
+                MessageEntityKind::Code
+            }
+            "pre" => MessageEntityKind::Pre { language: None },
+            "a" => {
+                let href = required("href")?;
+                // Mentions are named after their text, so they are only made once closed.
+                if href.starts_with(USER_URL) {
+                    let href = href.to_string();
+                    self.open(name, None, position);
+                    self.open.last_mut().unwrap().href = Some(href);
+                    return Ok(());
+                }
+                link(href, "").ok_or_else(|| {
+                    MarkupError::new(MarkupErrorKind::InvalidUrl(href.to_string()), position)
+                })?
+            }
+            "tg-emoji" => {
+                let id = required("emoji-id")?;
+                custom_emoji(&format!("{EMOJI_URL}{id}")).ok_or_else(|| {
+                    MarkupError::new(MarkupErrorKind::InvalidUrl(id.to_string()), position)
+                })?
+            }
+            _ => {
+                return Err(MarkupError::new(
+                    MarkupErrorKind::UnknownTag(name),
+                    position,
+                ))
+            }
+        };
+        self.open(name, Some(kind), position);
+        Ok(())
+    }
+
+    fn close_html(&mut self, tag: &[char], position: usize) -> Result<()> {
+        let name = tag.iter().collect::<String>().trim().to_ascii_lowercase();
+        if self.top() != Some(name.as_str()) {
+            return Err(match self.open.last() {
+                Some(open) => MarkupError::new(
+                    MarkupErrorKind::Unclosed(format!("<{}>", open.name)),
+                    open.position,
+                ),
+                None => {
+                    MarkupError::new(MarkupErrorKind::Unmatched(format!("</{name}>")), position)
+                }
+            });
+        }
+        let open = self.open.pop().unwrap();
+        let kind = match (open.kind, open.href) {
+            (Some(kind), _) => kind,
+            (None, Some(href)) => {
+                let text = &self.text[open.start_byte..];
+                link(&href, text).ok_or_else(|| {
+                    MarkupError::new(MarkupErrorKind::InvalidUrl(href), open.position)
+                })?
+            }
+            // The `<code>` of a `<pre>`, which holds the language.
+            (None, None) => return Ok(()),
+        };
+        self.add(kind, open.start);
+        Ok(())
+    }
+
+    /// Toggles a MarkdownV2 style such as `*`.
+    fn toggle_markdown_v2(
+        &mut self,
+        marker: &str,
+        kind: MessageEntityKind,
+        position: usize,
+    ) -> Result<()> {
+        if self.open.iter().any(|open| open.name == marker) {
+            let open = self.close(marker, position)?;
+            self.add(kind, open.start);
+        } else {
+            self.open(marker, Some(kind), position);
+        }
+        Ok(())
+    }
+
+    /// Ends a blockquote at the end of its last line.
+    fn close_quote(&mut self, position: usize) -> Result<()> {
+        if self.open.iter().any(|open| open.name == ">") {
+            let open = self.close(">", position)?;
+            self.add(MessageEntityKind::Blockquote, open.start);
+        }
+        Ok(())
+    }
+}
+
+impl Segments {
+    /// Writes the segments in Telegram's HTML style.
+    pub fn to_html(&self) -> String {
+        export::<Html>(self)
+    }
+
+    /// Writes the segments in Telegram's MarkdownV2 style.
+    ///
+    /// Blockquotes can only span whole lines, and should start at the beginning of one.
+    pub fn to_markdown_v2(&self) -> String {
+        export::<MarkdownV2>(self)
+    }
+
+    /// Reads text in Telegram's HTML style.
+    ///
+    /// Only the tags Telegram supports are accepted. `<a href="tg://user?id=…">` becomes a
+    /// mention of a user named after the link text.
+    pub fn from_html(html: &str) -> Result<Self> {
+        let chars: Vec<char> = html.chars().collect();
+        let mut builder = Builder::default();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '<' => {
+                    let end = find(&chars, i, '>')
+                        .ok_or_else(|| MarkupError::new(MarkupErrorKind::Unescaped('<'), i))?;
+                    match chars[i + 1..end].split_first() {
+                        Some(('/', tag)) => builder.close_html(tag, i)?,
+                        _ => builder.open_html(&chars[i + 1..end], i)?,
+                    }
+                    i = end + 1;
+                }
+                '&' => {
+                    let (c, next) = read_html_reference(&chars, i)?;
+                    builder.push(c);
+                    i = next;
+                }
+                '>' => return Err(MarkupError::new(MarkupErrorKind::Unescaped('>'), i)),
+                c => {
+                    builder.push(c);
+                    i += 1;
+                }
+            }
+        }
+        builder.finish(|name| format!("<{name}>"))
+    }
+
+    /// Reads text in Telegram's MarkdownV2 style.
+    ///
+    /// `[text](tg://user?id=…)` becomes a mention of a user named after the link text.
+    pub fn from_markdown_v2(markdown: &str) -> Result<Self> {
+        let chars: Vec<char> = markdown.chars().collect();
+        let starts_with = |at: usize, marker: &str| {
+            marker
+                .chars()
+                .enumerate()
+                .all(|(n, c)| chars.get(at + n) == Some(&c))
+        };
+        let mut builder = Builder::default();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if c == '\\' {
+                let escaped = chars
+                    .get(i + 1)
+                    .ok_or_else(|| MarkupError::new(MarkupErrorKind::Unescaped('\\'), i))?;
+                builder.push(*escaped);
+                i += 2;
+                continue;
+            }
+            if c == '\r' {
+                i += 1;
+                continue;
+            }
+
+            // Nothing but the closing marker has a meaning in code.
+            match builder.top() {
+                Some("```") if starts_with(i, "```") => {
+                    let open = builder.close("```", i)?;
+                    builder.add(open.kind.unwrap(), open.start);
+                    i += 3;
+                    continue;
+                }
+                Some("`") if c == '`' => {
+                    let open = builder.close("`", i)?;
+                    builder.add(MessageEntityKind::Code, open.start);
+                    i += 1;
+                    continue;
+                }
+                Some("```" | "`") => {
+                    builder.push(c);
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+
+            match c {
+                '*' => builder.toggle_markdown_v2("*", MessageEntityKind::Bold, i)?,
+                '_' if starts_with(i, "__") => {
+                    builder.toggle_markdown_v2("__", MessageEntityKind::Underline, i)?;
+                    i += 1;
+                }
+                '_' => builder.toggle_markdown_v2("_", MessageEntityKind::Italic, i)?,
+                '~' => builder.toggle_markdown_v2("~", MessageEntityKind::Strikethrough, i)?,
+                '|' if starts_with(i, "||") => {
+                    builder.toggle_markdown_v2("||", MessageEntityKind::Spoiler, i)?;
+                    i += 1;
+                }
+                '`' if starts_with(i, "```") => {
+                    let end = find(&chars, i + 3, '\n').ok_or_else(|| {
+                        MarkupError::new(MarkupErrorKind::Unclosed("```".to_string()), i)
+                    })?;
+                    let language: String = chars[i + 3..end].iter().collect();
+                    let language = (!language.is_empty()).then_some(language);
+                    builder.open("```", Some(MessageEntityKind::Pre { language }), i);
+                    i = end;
+                }
+                '`' => builder.open("`", Some(MessageEntityKind::Code), i),
+                '[' => builder.open("[", None, i),
+                '!' if starts_with(i, "![") => {
+                    builder.open("![", None, i);
+                    i += 1;
+                }
+                ']' if matches!(builder.top(), Some("[" | "![")) => {
+                    if chars.get(i + 1) != Some(&'(') {
+                        return Err(MarkupError::new(MarkupErrorKind::Unescaped(']'), i));
+                    }
+                    let mut url = String::new();
+                    let mut j = i + 2;
+                    loop {
+                        match chars.get(j) {
+                            Some('\\') if j + 1 < chars.len() => {
+                                url.push(chars[j + 1]);
+                                j += 2;
+                            }
+                            Some(')') => break,
+                            Some(&c) => {
+                                url.push(c);
+                                j += 1;
+                            }
+                            None => {
+                                return Err(MarkupError::new(
+                                    MarkupErrorKind::Unclosed("(".to_string()),
+                                    i + 1,
+                                ))
+                            }
+                        }
+                    }
+                    let name = builder.top().unwrap_or_default().to_string();
+                    let open = builder.close(&name, i)?;
+                    let kind = if name == "![" {
+                        custom_emoji(&url)
+                    } else {
+                        link(&url, &builder.text[open.start_byte..])
+                    };
+                    let kind = kind
+                        .ok_or_else(|| MarkupError::new(MarkupErrorKind::InvalidUrl(url), i + 2))?;
+                    builder.add(kind, open.start);
+                    i = j;
+                }
+                '>' if builder.at_line_start() => {
+                    if !builder.open.iter().any(|open| open.name == ">") {
+                        builder.open(">", Some(MessageEntityKind::Blockquote), i);
+                    }
+                }
+                '\n' => {
+                    if chars.get(i + 1) != Some(&'>') {
+                        builder.close_quote(i)?;
+                    }
+                    builder.push('\n');
+                }
+                c if MARKDOWN_V2_RESERVED.contains(&c) => {
+                    return Err(MarkupError::new(MarkupErrorKind::Unescaped(c), i))
+                }
+                c => builder.push(c),
+            }
+            i += 1;
+        }
+        builder.close_quote(chars.len())?;
+        builder.finish(ToString::to_string)
+    }
+}
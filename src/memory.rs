@@ -1,66 +1,222 @@
-use eyre::{ContextCompat, Report};
-use lru_cache::LruCache;
-use teloxide::types::{ChatId, Message, MessageId, User};
-
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-pub struct MessageMeta {
-    pub chat_id: ChatId,
-    pub message_id: MessageId,
-    pub sender: User,
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use eyre::{ContextCompat, Result};
+use hithit_bot::booking::{Booked, BookingStore, CacheStats, MessageKey};
+use sqlx::PgPool;
+use teloxide::types::{Message, MessageId, UserId};
+use tracing::{debug, warn};
+
+/// `msg` as one side of a booking.
+pub fn booked(msg: &Message) -> Result<Booked> {
+    Booked::from_message(msg).wrap_err("failed to get sender from message")
 }
 
-impl TryFrom<Message> for MessageMeta {
-    type Error = Report;
+/// Which bot reply answers which command, kept in Postgres so that it survives restarts and is
+/// shared between instances, with a [`BookingStore`] in front.
+///
+/// Bookings older than the max age are ignored, and removed by [`prune`](Self::prune).
+pub struct ReplyBooking {
+    pool: PgPool,
+    cache: BookingStore,
+}
 
-    fn try_from(msg: Message) -> Result<Self, Self::Error> {
-        Ok(Self {
-            chat_id: msg.chat.id,
-            message_id: msg.id,
-            sender: msg.from.wrap_err("failed to get sender from message")?,
-        })
-    }
+// When a booking made `age_secs` seconds ago, as told by Postgres, was made.
+fn booked_at(age_secs: f64) -> Instant {
+    Instant::now()
+        .checked_sub(Duration::from_secs_f64(age_secs.max(0.)))
+        .unwrap_or_else(Instant::now)
 }
 
-impl TryFrom<&Message> for MessageMeta {
-    type Error = Report;
-
-    fn try_from(msg: &Message) -> Result<Self, Self::Error> {
-        Ok(Self {
-            chat_id: msg.chat.id,
-            message_id: msg.id,
-            sender: msg
-                .from
-                .clone()
-                .wrap_err("failed to get sender from message")?,
-        })
-    }
+/// What operators see of the bookings.
+#[derive(Debug, Clone)]
+pub struct BookingStats {
+    /// Hits are lookups answered by the cache, misses went to the database.
+    pub cache: CacheStats,
+    /// Bookings in the database that haven't expired, if it could be reached.
+    pub stored: Option<i64>,
+    pub max_age: Duration,
 }
 
-pub struct ReplyBooking {
-    forward_map: LruCache<MessageMeta, MessageMeta>,
-    reverse_map: LruCache<MessageMeta, MessageMeta>,
+impl Display for BookingStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let stored = self
+            .stored
+            .map_or_else(|| String::from("unknown"), |stored| stored.to_string());
+        writeln!(
+            f,
+            "Reply bookings: {}/{} cached, {stored} stored, max age {}s.",
+            self.cache.len,
+            self.cache.capacity,
+            self.max_age.as_secs()
+        )?;
+        write!(
+            f,
+            "Hits: {}, misses: {}, evictions: {}.",
+            self.cache.hits, self.cache.misses, self.cache.evictions
+        )
+    }
 }
 
 impl ReplyBooking {
-    pub fn with_capacity(capacity: usize) -> Self {
+    /// Telegram only lets bots delete messages for 48 hours, after which a reply can't be
+    /// retracted anyway.
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(48 * 60 * 60);
+
+    pub fn new(pool: PgPool, capacity: usize, max_age: Duration) -> Self {
         Self {
-            forward_map: LruCache::new(capacity),
-            reverse_map: LruCache::new(capacity),
+            pool,
+            cache: BookingStore::new(capacity, max_age),
         }
     }
-    pub fn book(&mut self, replied_to: MessageMeta, reply: MessageMeta) {
-        self.forward_map.insert(replied_to.clone(), reply.clone());
-        self.reverse_map.insert(reply, replied_to);
+
+    /// Books `reply` as the answer to `command`.
+    pub async fn book(&self, command: Booked, reply: Booked) -> Result<()> {
+        // Replies are always sent to the chat of the command. Edits keep the reply, and so the
+        // time it was sent at.
+        let row = sqlx::query!(
+            "INSERT INTO reply_booking (chat_id, command_id, command_sender_id, reply_id, \
+             reply_sender_id, created_at) VALUES ($1, $2, $3, $4, $5, now()) \
+             ON CONFLICT (chat_id, command_id) DO UPDATE SET command_sender_id = $3, \
+             reply_id = $4, reply_sender_id = $5, created_at = CASE \
+             WHEN reply_booking.reply_id = $4 THEN reply_booking.created_at ELSE now() END \
+             RETURNING extract(epoch FROM now() - created_at)::float8 AS \"age!\"",
+            command.message.chat_id.0,
+            command.message.message_id.0,
+            command.sender.0.cast_signed(),
+            reply.message.message_id.0,
+            reply.sender.0.cast_signed()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        self.cache.book_at(command, reply, booked_at(row.age));
+        Ok(())
     }
-    pub fn forward_lookup(&mut self, replied_to: &MessageMeta) -> Option<&MessageMeta> {
-        self.forward_map.get_mut(replied_to).map(|m| &*m)
+
+    /// The reply to `command`, if there is one.
+    pub async fn forward_lookup(&self, command: MessageKey) -> Option<Booked> {
+        if let Some(reply) = self.cache.forward_lookup(command) {
+            return Some(reply);
+        }
+
+        let row = sqlx::query!(
+            "SELECT command_sender_id, reply_id, reply_sender_id, \
+             extract(epoch FROM now() - created_at)::float8 AS \"age!\" \
+             FROM reply_booking WHERE chat_id = $1 AND command_id = $2 \
+             AND created_at > now() - make_interval(secs => $3)",
+            command.chat_id.0,
+            command.message_id.0,
+            self.cache.max_age().as_secs_f64()
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or_else(|e| {
+            warn!(?e, "failed to look up reply");
+            None
+        })?;
+        let reply = Booked {
+            message: MessageKey {
+                chat_id: command.chat_id,
+                message_id: MessageId(row.reply_id),
+            },
+            sender: UserId(row.reply_sender_id.cast_unsigned()),
+        };
+
+        let command = Booked {
+            message: command,
+            sender: UserId(row.command_sender_id.cast_unsigned()),
+        };
+        self.cache.book_at(command, reply, booked_at(row.age));
+        Some(reply)
+    }
+
+    /// Like [`forward_lookup`](Self::forward_lookup), but asks the database even if the reply is
+    /// cached, as another instance may have replaced or forgotten the booking since.
+    pub async fn forward_lookup_stored(&self, command: MessageKey) -> Option<Booked> {
+        self.cache.forget(command);
+        self.forward_lookup(command).await
     }
-    pub fn reverse_lookup(&mut self, reply: &MessageMeta) -> Option<&MessageMeta> {
-        self.reverse_map.get_mut(reply).map(|m| &*m)
+
+    /// The command `reply` answers, if it is a reply of the bot.
+    pub async fn reverse_lookup(&self, reply: MessageKey) -> Option<Booked> {
+        if let Some(command) = self.cache.reverse_lookup(reply) {
+            return Some(command);
+        }
+
+        let row = sqlx::query!(
+            "SELECT command_id, command_sender_id, reply_sender_id, \
+             extract(epoch FROM now() - created_at)::float8 AS \"age!\" \
+             FROM reply_booking WHERE chat_id = $1 AND reply_id = $2 \
+             AND created_at > now() - make_interval(secs => $3)",
+            reply.chat_id.0,
+            reply.message_id.0,
+            self.cache.max_age().as_secs_f64()
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap_or_else(|e| {
+            warn!(?e, "failed to look up command");
+            None
+        })?;
+        let command = Booked {
+            message: MessageKey {
+                chat_id: reply.chat_id,
+                message_id: MessageId(row.command_id),
+            },
+            sender: UserId(row.command_sender_id.cast_unsigned()),
+        };
+
+        let reply = Booked {
+            message: reply,
+            sender: UserId(row.reply_sender_id.cast_unsigned()),
+        };
+        self.cache.book_at(command, reply, booked_at(row.age));
+        Some(command)
     }
-    pub fn forget(&mut self, replied_to: &MessageMeta) {
-        if let Some(reply) = self.forward_map.remove(replied_to) {
-            self.reverse_map.remove(&reply);
+
+    pub async fn forget(&self, command: MessageKey) -> Result<()> {
+        self.cache.forget(command);
+        sqlx::query!(
+            "DELETE FROM reply_booking WHERE chat_id = $1 AND command_id = $2",
+            command.chat_id.0,
+            command.message_id.0
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Removes the bookings that are older than the max age.
+    pub async fn prune(&self) {
+        self.cache.remove_expired();
+        let pruned = sqlx::query!(
+            "DELETE FROM reply_booking WHERE created_at <= now() - make_interval(secs => $1)",
+            self.cache.max_age().as_secs_f64()
+        )
+        .execute(&self.pool)
+        .await;
+        match pruned {
+            Ok(pruned) => debug!(rows = pruned.rows_affected(), "pruned reply bookings"),
+            Err(e) => warn!(?e, "failed to prune reply bookings"),
+        }
+    }
+
+    pub async fn stats(&self) -> BookingStats {
+        // Expired bookings that haven't been pruned yet are as good as gone.
+        let stored = sqlx::query_scalar!(
+            "SELECT count(*) AS \"count!\" FROM reply_booking \
+             WHERE created_at > now() - make_interval(secs => $1)",
+            self.cache.max_age().as_secs_f64()
+        )
+        .fetch_one(&self.pool)
+        .await
+        .inspect_err(|e| warn!(?e, "failed to count reply bookings"))
+        .ok();
+        BookingStats {
+            cache: self.cache.stats(),
+            stored,
+            max_age: self.cache.max_age(),
         }
     }
 }
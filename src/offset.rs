@@ -0,0 +1,118 @@
+use std::ops::Add;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A position in text, counted in one of the units below.
+///
+/// Each unit is its own type, so offsets in different units can't be mixed up.
+pub trait TextOffset: Copy + Ord + private::Sealed {
+    /// The offset of the end of `text`.
+    fn end_of(text: &str) -> Self;
+
+    /// The byte index of this offset in `text`, or `None` if `text` is not that long.
+    ///
+    /// An offset inside a character is rounded down to the start of the character.
+    fn byte_index(self, text: &str) -> Option<usize>;
+}
+
+macro_rules! offset {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+        pub struct $name(pub usize);
+
+        impl private::Sealed for $name {}
+
+        impl Add for $name {
+            type Output = Self;
+
+            fn add(self, rhs: Self) -> Self {
+                Self(self.0 + rhs.0)
+            }
+        }
+
+        impl $name {
+            /// The distance from `rhs` to `self`, or `None` if `rhs` comes after `self`.
+            pub const fn checked_sub(self, rhs: Self) -> Option<Self> {
+                match self.0.checked_sub(rhs.0) {
+                    Some(offset) => Some(Self(offset)),
+                    None => None,
+                }
+            }
+        }
+    };
+}
+
+offset!(
+    /// UTF-16 code units, which Telegram counts entity offsets in.
+    Utf16Offset
+);
+offset!(
+    /// Unicode scalar values, i.e. Rust `char`s.
+    CharOffset
+);
+offset!(
+    /// Extended grapheme clusters, i.e. what users see as one character, such as `👩‍👩‍👧` or `é`.
+    GraphemeOffset
+);
+
+// The byte index after `n` units, where each char counts as `units` of them.
+fn byte_index_by(text: &str, n: usize, units: impl Fn(char) -> usize) -> Option<usize> {
+    let mut count = 0;
+    for (i, chr) in text.char_indices() {
+        count += units(chr);
+        if count > n {
+            return Some(i);
+        }
+    }
+    (count == n).then_some(text.len())
+}
+
+impl TextOffset for Utf16Offset {
+    fn end_of(text: &str) -> Self {
+        Self(text.encode_utf16().count())
+    }
+
+    fn byte_index(self, text: &str) -> Option<usize> {
+        byte_index_by(text, self.0, char::len_utf16)
+    }
+}
+
+impl TextOffset for CharOffset {
+    fn end_of(text: &str) -> Self {
+        Self(text.chars().count())
+    }
+
+    fn byte_index(self, text: &str) -> Option<usize> {
+        byte_index_by(text, self.0, |_| 1)
+    }
+}
+
+impl TextOffset for GraphemeOffset {
+    fn end_of(text: &str) -> Self {
+        Self(text.graphemes(true).count())
+    }
+
+    fn byte_index(self, text: &str) -> Option<usize> {
+        text.grapheme_indices(true)
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .nth(self.0)
+    }
+}
+
+/// Rounds the byte index `at` down to the start of the grapheme cluster it falls in.
+pub(crate) fn grapheme_boundary(text: &str, at: usize) -> usize {
+    if at >= text.len() {
+        return text.len();
+    }
+    text.grapheme_indices(true)
+        .map(|(i, _)| i)
+        .take_while(|&i| i <= at)
+        .last()
+        .unwrap_or(0)
+}
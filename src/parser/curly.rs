@@ -1,87 +1,345 @@
 use std::cmp::max;
 use std::collections::HashSet;
+use std::ops::Range;
 
-use pest::Parser;
+use pest::iterators::{Pair, Pairs};
+use pest::{Parser, Span};
 use pest_derive::Parser;
+use teloxide::types::MessageEntityKind;
 
-use crate::formatter::{Formatter, HoleIdent, Token};
+use crate::formatter::{Condition, Filter, Formatter, HoleIdent, Token, VARIABLES};
+use crate::parser::{ParseError, ParseErrorKind};
 use crate::segments::{Segment, Segments};
 
-pub type Error = pest::error::Error<Rule>;
-
 #[derive(Parser)]
 #[grammar = "fmt.pest"]
 struct FmtParser;
 
-#[allow(clippy::result_large_err)]
-pub fn parse(segments: &Segments) -> Result<Formatter, Error> {
-    let mut ast = vec![];
-    let mut anonymous_counter = 0;
-    let mut max_indexed = 0;
-    let mut named: HashSet<String> = HashSet::new();
+struct Block {
+    condition: Condition,
+    then: Vec<Token>,
+    otherwise: Option<Vec<Token>>,
+    span: Range<usize>,
+    source: String,
+}
+
+impl Block {
+    fn into_token(self) -> Token {
+        Token::Conditional {
+            condition: self.condition,
+            then: self.then,
+            otherwise: self.otherwise.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Scope {
+    ast: Vec<Token>,
+    blocks: Vec<Block>,
+}
+
+impl Scope {
+    fn sink(&mut self) -> &mut Vec<Token> {
+        match self.blocks.last_mut() {
+            Some(Block {
+                otherwise: Some(otherwise),
+                ..
+            }) => otherwise,
+            Some(Block { then, .. }) => then,
+            None => &mut self.ast,
+        }
+    }
+    fn finish(mut self) -> Result<Vec<Token>, ParseError> {
+        match self.blocks.pop() {
+            Some(block) => Err(ParseError {
+                kind: ParseErrorKind::UnclosedBlock(block.source),
+                span: block.span,
+            }),
+            None => Ok(self.ast),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    anonymous_counter: usize,
+    max_indexed: usize,
+    named: HashSet<String>,
+    // The first `{` that doesn't start a hole, choice or block.
+    stray: Option<ParseError>,
+}
+
+// The whole message is parsed at once. Pest spans are mapped back to the segments they come from,
+// so that literal text keeps its own formatting and errors point into the original message.
+struct Source<'a> {
+    text: String,
+    base: usize,
+    starts: Vec<usize>,
+    kinds: Vec<&'a HashSet<MessageEntityKind>>,
+}
+
+impl<'a> Source<'a> {
+    fn new(segments: &'a Segments, base: usize) -> Self {
+        let mut text = String::new();
+        let mut starts = Vec::with_capacity(segments.len());
+        let mut kinds = Vec::with_capacity(segments.len());
+        for segment in &**segments {
+            starts.push(text.len());
+            kinds.push(&segment.kind);
+            text.push_str(&segment.text);
+        }
+        Self {
+            text,
+            base,
+            starts,
+            kinds,
+        }
+    }
+    fn offset(&self, pos: usize) -> usize {
+        self.base + self.text[..pos].encode_utf16().count()
+    }
+    fn span(&self, span: Span) -> Range<usize> {
+        self.offset(span.start())..self.offset(span.end())
+    }
+    fn error(&self, pair: &Pair<Rule>, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            kind,
+            span: self.span(pair.as_span()),
+        }
+    }
+    fn kind_at(&self, pos: usize) -> &'a HashSet<MessageEntityKind> {
+        self.kinds[self.starts.partition_point(|&start| start <= pos) - 1]
+    }
+    // A hole crossing a formatting boundary keeps only the formatting shared by all of its characters.
+    fn common_kind(&self, span: &Span) -> HashSet<MessageEntityKind> {
+        let end_of = |idx: usize| self.starts.get(idx + 1).copied().unwrap_or(self.text.len());
+        (0..self.kinds.len())
+            .filter(|&idx| {
+                self.starts[idx] < end_of(idx)
+                    && self.starts[idx] < span.end()
+                    && end_of(idx) > span.start()
+            })
+            .map(|idx| self.kinds[idx])
+            .fold(None, |acc: Option<HashSet<_>>, kind| {
+                Some(acc.map_or_else(
+                    || kind.clone(),
+                    |acc| acc.intersection(kind).cloned().collect(),
+                ))
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+struct Buffer {
+    text: String,
+    kind: HashSet<MessageEntityKind>,
+}
+
+impl Buffer {
+    fn push(&mut self, scope: &mut Scope, text: &str, kind: &HashSet<MessageEntityKind>) {
+        if &self.kind != kind {
+            self.flush(scope);
+            self.kind.clone_from(kind);
+        }
+        self.text.push_str(text);
+    }
+    fn flush(&mut self, scope: &mut Scope) {
+        if !self.text.is_empty() {
+            scope.sink().push(Token::Segment(Segment {
+                kind: self.kind.clone(),
+                text: std::mem::take(&mut self.text),
+            }));
+        }
+    }
+}
 
-    for segment in &**segments {
-        let mut pairs = FmtParser::parse(Rule::formatter, &segment.text)?;
-        let pair = pairs.next().unwrap();
+pub fn parse(segments: &Segments, offset: usize) -> Result<Formatter, ParseError> {
+    let mut scope = Scope::default();
+    let mut state = State::default();
+    let source = Source::new(segments, offset);
 
+    let mut pairs = FmtParser::parse(Rule::formatter, &source.text)
+        .expect("template grammar accepts any input");
+    let pair = pairs.next().unwrap();
+    match pair.as_rule() {
+        Rule::formatter => walk(pair.into_inner(), &source, &mut scope, &mut state)?,
+        Rule::EOI => {}
+        _ => unreachable!(),
+    };
+
+    let formatter = Formatter {
+        data: scope.finish()?,
+        indexed: max(state.anonymous_counter, state.max_indexed),
+        named: state.named,
+    };
+    // A lone `{` or `{>_<}` is most likely an emoticon unless the message is meant to be a
+    // template.
+    match state.stray {
+        Some(err) if formatter.is_templated() => Err(err),
+        _ => Ok(formatter),
+    }
+}
+
+fn walk(
+    pairs: Pairs<Rule>,
+    source: &Source,
+    scope: &mut Scope,
+    state: &mut State,
+) -> Result<(), ParseError> {
+    let mut buffer = Buffer::default();
+
+    for pair in pairs {
+        if !matches!(
+            pair.as_rule(),
+            Rule::char | Rule::branch_char | Rule::escaped | Rule::invalid | Rule::unclosed
+        ) {
+            buffer.flush(scope);
+        }
         match pair.as_rule() {
-            Rule::formatter => {
-                let mut buffer = String::new();
-
-                let pairs = pair.into_inner();
-                for pair in pairs {
-                    match pair.as_rule() {
-                        Rule::ident => {
-                            if !buffer.is_empty() {
-                                ast.push(Token::Segment(Segment {
-                                    kind: segment.kind.clone(),
-                                    text: std::mem::take(&mut buffer),
-                                }));
-                            };
-                            ast.push(Token::Hole {
-                                kind: segment.kind.clone(),
-                                ident: {
-                                    let ident: &str = pair.as_str();
-                                    if ident.is_empty() {
-                                        anonymous_counter += 1;
-                                        HoleIdent::Anonymous
-                                    } else if let Ok(idx) = ident.parse::<usize>() {
-                                        max_indexed = max(max_indexed, idx + 1);
-                                        HoleIdent::Indexed(idx)
-                                    } else {
-                                        named.insert(ident.to_string());
-                                        HoleIdent::Named(ident.to_string())
-                                    }
-                                },
-                            });
-                        }
-                        Rule::char => buffer.push_str(pair.as_str()),
-                        Rule::escaped => {
-                            buffer.push(match pair.as_str() {
-                                "{{" => '{',
-                                "}}" => '}',
-                                _ => unreachable!(),
-                            });
-                        }
-                        Rule::EOI => {}
-                        _ => unreachable!(),
+            Rule::hole => {
+                let span = source.span(pair.as_span());
+                let kind = source.common_kind(&pair.as_span());
+                let mut inner = pair.clone().into_inner().peekable();
+                let ident = inner.next().unwrap().as_str();
+                let fallback = inner
+                    .next_if(|pair| pair.as_rule() == Rule::fallback)
+                    .map(|pair| pair.into_inner().map(|pair| unescape(&pair)).collect());
+                let filters = match inner
+                    .map(|filter| filter.as_str().parse::<Filter>().map_err(|()| filter))
+                    .collect::<Result<_, _>>()
+                {
+                    Ok(filters) => filters,
+                    Err(filter) if ident.is_empty() || is_variable(ident) => {
+                        return Err(source.error(
+                            &filter,
+                            ParseErrorKind::UnknownFilter(filter.as_str().to_string()),
+                        ));
                     }
-                }
-                if !buffer.is_empty() {
-                    ast.push(Token::Segment(Segment {
-                        kind: segment.kind.clone(),
-                        text: std::mem::take(&mut buffer),
-                    }));
+                    Err(_) => {
+                        scope.sink().push(word_choice(&pair, source));
+                        continue;
+                    }
+                };
+                let hole = Token::Hole {
+                    kind,
+                    ident: if ident.is_empty() {
+                        state.anonymous_counter += 1;
+                        HoleIdent::Anonymous
+                    } else if let Ok(idx) = ident.parse::<usize>() {
+                        state.max_indexed = max(state.max_indexed, idx + 1);
+                        HoleIdent::Indexed(idx)
+                    } else {
+                        state.named.insert(ident.to_string());
+                        HoleIdent::Named(ident.to_string())
+                    },
+                    fallback,
+                    filters,
+                    span,
                 };
+                scope.sink().push(hole);
+            }
+            Rule::choice => {
+                let branches = pair
+                    .into_inner()
+                    .map(|branch| {
+                        let mut branch_scope = Scope::default();
+                        walk(branch.into_inner(), source, &mut branch_scope, state)?;
+                        branch_scope.finish()
+                    })
+                    .collect::<Result<_, _>>()?;
+                scope.sink().push(Token::Choice { branches });
+            }
+            Rule::block_open => {
+                let span = source.span(pair.as_span());
+                let text = pair.as_str().to_string();
+                let condition = pair.into_inner().next().unwrap();
+                scope.blocks.push(Block {
+                    condition: condition.as_str().parse().map_err(|()| {
+                        source.error(
+                            &condition,
+                            ParseErrorKind::UnknownCondition(condition.as_str().to_string()),
+                        )
+                    })?,
+                    then: vec![],
+                    otherwise: None,
+                    span,
+                    source: text,
+                });
+            }
+            Rule::block_else => match scope.blocks.last_mut() {
+                Some(block) if block.otherwise.is_none() => {
+                    block.otherwise = Some(vec![]);
+                }
+                Some(_) => return Err(source.error(&pair, ParseErrorKind::DuplicatedElse)),
+                None => return Err(source.error(&pair, ParseErrorKind::StrayElse)),
+            },
+            Rule::block_close => {
+                let block = scope
+                    .blocks
+                    .pop()
+                    .ok_or_else(|| source.error(&pair, ParseErrorKind::UnmatchedClose))?;
+                scope.sink().push(block.into_token());
+            }
+            Rule::invalid | Rule::unclosed => {
+                state.stray.get_or_insert_with(|| {
+                    let kind = if pair.as_rule() == Rule::invalid {
+                        ParseErrorKind::InvalidBrace(pair.as_str().to_string())
+                    } else {
+                        ParseErrorKind::UnclosedBrace
+                    };
+                    source.error(&pair, kind)
+                });
+                buffer.push(scope, pair.as_str(), source.kind_at(pair.as_span().start()));
+            }
+            Rule::char | Rule::branch_char | Rule::escaped => {
+                buffer.push(
+                    scope,
+                    unescape(&pair),
+                    source.kind_at(pair.as_span().start()),
+                );
             }
             Rule::EOI => {}
             _ => unreachable!(),
-        };
+        }
     }
+    buffer.flush(scope);
+    Ok(())
+}
 
-    Ok(Formatter {
-        data: ast,
-        indexed: max(anonymous_counter, max_indexed),
-        named,
-    })
+fn is_variable(ident: &str) -> bool {
+    ident.parse::<usize>().is_ok() || VARIABLES.contains(&ident)
+}
+
+// `{hit|kick}` looks like a hole but has no filters to apply, so it picks one of its words.
+fn word_choice(pair: &Pair<Rule>, source: &Source) -> Token {
+    let text = pair.as_str();
+    let mut start = pair.as_span().start() + 1;
+    let branches = text[1..text.len() - 1]
+        .split('|')
+        .map(|word| {
+            let kind = source.kind_at(start);
+            start += word.len() + 1;
+            let word = word.replace("{{", "{").replace("}}", "}");
+            if word.is_empty() {
+                vec![]
+            } else {
+                vec![Token::Segment(Segment {
+                    kind: kind.clone(),
+                    text: word,
+                })]
+            }
+        })
+        .collect();
+    Token::Choice { branches }
+}
+
+fn unescape<'a>(pair: &Pair<'a, Rule>) -> &'a str {
+    match (pair.as_rule(), pair.as_str()) {
+        (Rule::escaped, "{{") => "{",
+        (Rule::escaped, "}}") => "}",
+        (Rule::escaped, _) => unreachable!(),
+        (_, text) => text,
+    }
 }
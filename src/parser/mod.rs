@@ -1,33 +1,148 @@
-use crate::error::{Error, Result};
-use crate::formatter::Formatter;
-use crate::segments::Segments;
+use std::ops::Range;
+
+use teloxide::types::MessageEntityKind;
+use thiserror::Error;
 
-pub type ParseError = curly::Error;
+use crate::error::Result;
+use crate::formatter::Formatter;
+use crate::locale::Locale;
+use crate::segments::{Segment, Segments};
 
 mod curly;
 mod naive;
 
+/// What went wrong while parsing a template.
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub enum ParseErrorKind {
+    #[error("unclosed `{{`")]
+    UnclosedBrace,
+    #[error("`{0}` is not a hole, choice or block")]
+    InvalidBrace(String),
+    #[error("unclosed block `{0}`")]
+    UnclosedBlock(String),
+    #[error("unknown filter `{0}`")]
+    UnknownFilter(String),
+    #[error("unknown condition `{0}`")]
+    UnknownCondition(String),
+    #[error("duplicated `{{:}}` in block")]
+    DuplicatedElse,
+    #[error("`{{:}}` outside of a block")]
+    StrayElse,
+    #[error("unmatched `{{/}}`")]
+    UnmatchedClose,
+}
+
+/// A template parse error, located in UTF-16 offsets of the original message.
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+#[error("{kind} at position {}", span.start)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Range<usize>,
+}
+
+/// Turns a message into a [`Formatter`].
+///
+/// Messages with holes (`{}`, `{0}`, `{name}`), conditional blocks or alternatives (`{~a|b}`) are
+/// parsed as curly templates. Otherwise, if `try_naive` is set, the message is read as a plain verb such as
+/// `打` and rendered as `{sender} 打了 {receiver}`.
 pub struct Parser {
     input: Segments,
     try_naive: bool,
+    offset: usize,
+    locale: Locale,
+    mentions: Vec<Segment>,
 }
 
 impl Parser {
     pub const fn new(input: Segments, try_naive: bool) -> Self {
-        Self { input, try_naive }
+        Self {
+            input,
+            try_naive,
+            offset: 0,
+            locale: Locale::ZH_HANS,
+            mentions: vec![],
+        }
+    }
+    /// Sets the UTF-16 offset of `input` in the original message, so that errors point into the
+    /// message rather than into the template alone.
+    pub const fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+    /// Takes the users mentioned at the end of the input, e.g. `@alice @bob` in `打 @alice @bob`,
+    /// so that they are hit besides the replied user.
+    ///
+    /// Templates that don't refer to the receivers keep them as part of their text, as in
+    /// `{s} 打了 @alice`.
+    pub fn take_mentions(mut self) -> Self {
+        let Some((rest, mentions)) = split_mentions(&self.input) else {
+            return self;
+        };
+        // Invalid templates are left alone, so that the error points into the whole input.
+        let take = match curly::parse(&self.input, self.offset) {
+            Ok(formatter) if formatter.is_templated() => formatter.refers_to_receivers(),
+            Ok(_) => self.try_naive,
+            Err(_) => false,
+        };
+        if take {
+            self.input = rest;
+            self.mentions = mentions;
+        }
+        self
+    }
+    /// The users mentioned after the command, see [`take_mentions`](Self::take_mentions).
+    pub fn mentions(&self) -> &[Segment] {
+        &self.mentions
+    }
+    /// Sets the locale used by naive mode.
+    pub const fn with_locale(mut self, locale: Locale) -> Self {
+        self.locale = locale;
+        self
     }
 }
 
 impl Parser {
-    pub fn try_as_formatter(&self) -> Result<Formatter> {
-        let with_curly = curly::parse(&self.input)?;
+    /// Returns `None` if the input is not a template and naive mode is disabled.
+    pub fn try_as_formatter(&self) -> Result<Option<Formatter>> {
+        let with_curly = curly::parse(&self.input, self.offset)?;
 
-        if with_curly.indexed_holes() > 0 || !with_curly.named_holes().is_empty() {
-            Ok(with_curly)
+        Ok(if with_curly.is_templated() {
+            Some(with_curly)
         } else if self.try_naive {
-            Ok(naive::parse(&self.input))
+            Some(naive::parse(&self.input, &self.locale))
         } else {
-            Err(Error::ShouldNotHandle)
+            None
+        })
+    }
+}
+
+// Splits off the mentions at the end of `segments`, unless there is nothing else.
+fn split_mentions(segments: &Segments) -> Option<(Segments, Vec<Segment>)> {
+    let is_mention = |segment: &Segment| {
+        segment.kind.iter().any(|kind| {
+            matches!(
+                kind,
+                MessageEntityKind::Mention | MessageEntityKind::TextMention { .. }
+            )
+        })
+    };
+
+    let mut mentions = vec![];
+    let mut rest = segments.len();
+    for (i, segment) in segments.iter().enumerate().rev() {
+        if is_mention(segment) {
+            mentions.push(segment.clone());
+            rest = i;
+        } else if !segment.text.trim().is_empty() {
+            break;
         }
     }
+    // Keep them if there is nothing else, as there would be no verb left.
+    if mentions.is_empty() || segments.iter().take(rest).all(|s| s.text.trim().is_empty()) {
+        return None;
+    }
+
+    mentions.reverse();
+    let rest = Segments::from(segments.iter().take(rest).cloned()).trim_end();
+    Some((rest, mentions))
 }
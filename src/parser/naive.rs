@@ -1,16 +1,126 @@
 use std::collections::HashSet;
 use std::iter::FromIterator;
+use std::ops::Range;
 
 use crate::formatter::{Formatter, HoleIdent, Token};
+use crate::inflect::Inflection;
+use crate::locale::Locale;
+use crate::offset::CharOffset;
 use crate::segments::{Segment, Segments};
 
-pub fn parse(segments: &Segments) -> Formatter {
+// Inflects the first word, which naive mode treats as the verb.
+//
+// The part of the verb that is kept stays where it is, and the changed ending goes to the segment
+// holding the last kept character, so that `**slap**` becomes `**slapped**`.
+fn inflect_verb(segments: &Segments, inflection: Inflection) -> Segments {
+    let verb: String = segments
+        .iter()
+        .flat_map(|segment| segment.text.chars())
+        .take_while(|c| !c.is_whitespace())
+        .collect();
+    let past = inflection.apply(&verb);
+    if past == verb {
+        return segments.clone();
+    }
+
+    let kept = verb
+        .chars()
+        .zip(past.chars())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let removed = kept..verb.chars().count();
+    let mut ending = Some(past.chars().skip(kept).collect::<String>());
+
+    let mut pos = 0;
+    segments
+        .iter()
+        .filter_map(|segment| {
+            let mut text = String::new();
+            for c in segment.text.chars() {
+                if pos == kept {
+                    text.extend(ending.take());
+                }
+                if !removed.contains(&pos) {
+                    text.push(c);
+                }
+                pos += 1;
+            }
+            if pos == kept && !text.is_empty() {
+                text.extend(ending.take());
+            }
+            (!text.is_empty()).then(|| Segment {
+                kind: segment.kind.clone(),
+                text,
+            })
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+// Inserts `text` in front of the `at`-th character.
+//
+// Inside a segment the text takes its formatting, and between segments it keeps only what both
+// sides have in common, like a hole crossing a formatting boundary.
+fn insert_at(segments: &Segments, CharOffset(at): CharOffset, text: &str) -> Segments {
+    let mut output: Vec<Segment> = Vec::with_capacity(segments.len() + 2);
+    let mut pos = 0;
+    for segment in segments.iter() {
+        let len = segment.text.chars().count();
+        if (pos..pos + len).contains(&at) {
+            let chars: Vec<_> = segment.text.chars().collect();
+            let (head, tail) = chars.split_at(at - pos);
+            let kind = if head.is_empty() {
+                output.last().map_or_else(HashSet::new, |prev| {
+                    prev.kind.intersection(&segment.kind).cloned().collect()
+                })
+            } else {
+                output.push(Segment {
+                    kind: segment.kind.clone(),
+                    text: String::from_iter(head),
+                });
+                segment.kind.clone()
+            };
+            output.push(Segment {
+                kind,
+                text: String::from(text),
+            });
+            output.push(Segment {
+                kind: segment.kind.clone(),
+                text: String::from_iter(tail),
+            });
+        } else {
+            output.push(segment.clone());
+        }
+        pos += len;
+    }
+    if at >= pos {
+        output.push(Segment::plain(text));
+    }
+    output.into()
+}
+
+pub fn parse(segments: &Segments, locale: &Locale) -> Formatter {
+    let segments = &inflect_verb(segments, locale.inflection);
+    let segments = &if segments
+        .iter()
+        .any(|segment| segment.text.contains(char::is_whitespace))
+    {
+        segments.clone()
+    } else {
+        locale.aspect_position(&segments.text()).map_or_else(
+            || segments.clone(),
+            |at| insert_at(segments, at, locale.aspect_marker),
+        )
+    };
     let mut bypass = false;
 
     let mut data: Vec<Token> = vec![
         Token::Hole {
             kind: HashSet::new(),
             ident: HoleIdent::Named(String::from("sender")),
+            fallback: None,
+            filters: vec![],
+            span: Range::default(),
         },
         Token::Segment(Segment::empty()),
     ]
@@ -32,7 +142,10 @@ pub fn parse(segments: &Segments) -> Formatter {
                     }),
                     Token::Hole {
                         kind: segment.kind.clone(),
-                        ident: HoleIdent::Indexed(1),
+                        ident: HoleIdent::Named(String::from("receivers")),
+                        fallback: None,
+                        filters: vec![],
+                        span: Range::default(),
                     },
                     Token::Segment(Segment {
                         kind: segment.kind.clone(),
@@ -44,34 +157,19 @@ pub fn parse(segments: &Segments) -> Formatter {
     }))
     .collect();
 
-    if bypass {
-        Formatter {
-            data,
-            indexed: 1,
-            named: HashSet::new(),
-        }
-    } else {
-        data.push(Token::Segment(Segment {
-            text: String::from(
-                if segments
-                    .back()
-                    .map_or(false, |segment| segment.text.ends_with('了'))
-                {
-                    " "
-                } else {
-                    "了 "
-                },
-            ),
-            kind: HashSet::new(),
-        }));
+    if !bypass {
+        data.push(Token::Segment(Segment::plain(" ")));
         data.push(Token::Hole {
             kind: HashSet::new(),
-            ident: HoleIdent::Indexed(1),
+            ident: HoleIdent::Named(String::from("receivers")),
+            fallback: None,
+            filters: vec![],
+            span: Range::default(),
         });
-        Formatter {
-            data,
-            indexed: 0,
-            named: HashSet::new(),
-        }
+    }
+    Formatter {
+        data,
+        indexed: 0,
+        named: HashSet::new(),
     }
 }
@@ -1,107 +1,301 @@
-use crate::error::{Error, Result};
-use crate::formatter::FormatContext;
+use chrono::FixedOffset;
+use hithit_bot::error::{ExportedError, Format, Parse};
+use hithit_bot::formatter::{Filter, FormatContext, Target, DATE_FORMAT, TIME_FORMAT};
+use hithit_bot::locale::Locale;
+use hithit_bot::offset::CharOffset;
+use hithit_bot::segments::{Segment, Segments};
+use maplit::hashset;
+use teloxide::types::{Message, MessageEntityKind, User, UserId};
+use thiserror::Error;
+
 use crate::memory::ReplyBooking;
-use crate::parser::Parser;
-use crate::segments::{Segment, Segments};
-use crate::{COMMAND_PREFIX, EXPLAIN_COMMAND, EXPLAIN_COMMAND_EXTENDED};
-use parking_lot::Mutex;
-use teloxide::types::{Message, User};
+use crate::settings::Names;
+use crate::{settings, users, COMMANDS, QUOTE_LIMIT};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("this message is not intended to be handled by this bot")]
+    ShouldNotHandle,
+    #[error(transparent)]
+    Exported(#[from] ExportedError),
+}
+
+impl From<Format> for Error {
+    fn from(value: Format) -> Self {
+        Self::Exported(value.into())
+    }
+}
+
+impl From<Parse> for Error {
+    fn from(value: Parse) -> Self {
+        Self::Exported(value.into())
+    }
+}
+
+pub trait ErrorExt<T> {
+    fn lift_should_not_handle(self) -> Result<T, Error>;
+}
+
+impl<T> ErrorExt<Result<T, ExportedError>> for Result<T, Error> {
+    fn lift_should_not_handle(self) -> Result<Result<T, ExportedError>, Error> {
+        match self {
+            Ok(t) => Ok(Ok(t)),
+            Err(Error::ShouldNotHandle) => Err(Error::ShouldNotHandle),
+            Err(Error::Exported(e)) => Ok(Err(e)),
+        }
+    }
+}
 
 pub async fn process(
     bot_user: &User,
-    booking: &Mutex<ReplyBooking>,
+    booking: &ReplyBooking,
     msg: &Message,
     pool: sqlx::PgPool,
 ) -> Result<Segments> {
     let text = msg.text().ok_or(Error::ShouldNotHandle)?;
     let entities = msg.entities().ok_or(Error::ShouldNotHandle)?;
 
+    // Only commands need the settings of the chat, so other messages don't touch the database.
     if !text.starts_with('/') {
         return Err(Error::ShouldNotHandle);
     }
+    let chat = settings::chat(&pool, msg.chat.id).await;
 
-    let compatibility = sqlx::query!("SELECT id FROM compatibility WHERE id = $1", msg.chat.id.0)
-        .fetch_optional(&pool)
-        .await
-        .map_or(false, |row| row.is_some());
-
-    let fmt_ctx = build_format_ctx(bot_user, &mut booking.lock(), msg)?;
-
-    let parser = if text.starts_with(EXPLAIN_COMMAND_EXTENDED.get().unwrap()) {
-        Segments::build(text, entities)
-            .drain_head(EXPLAIN_COMMAND_EXTENDED.get().unwrap().len() + 1)
-            .map(|segments| Parser::new(segments, true))
-    } else if text.starts_with(EXPLAIN_COMMAND) {
-        Segments::build(text, entities)
-            .drain_head(EXPLAIN_COMMAND.len() + 1)
-            .map(|segments| Parser::new(segments, true))
-    } else {
-        text.chars().nth(1).and_then(|chr| {
-            if chr.len_utf8() > 1 {
-                Segments::build(text, entities)
-                    .drain_head(1)
-                    .map(|segments| Parser::new(segments, !compatibility))
-            } else if chr == *COMMAND_PREFIX.get().unwrap() {
-                Segments::build(text, entities)
-                    .drain_head(2)
-                    .map(|segments| Parser::new(segments, !compatibility))
-            } else {
-                Segments::build(text, entities)
-                    .drain_head(1)
-                    .map(|segments| Parser::new(segments, false))
+    let parser = COMMANDS
+        .get()
+        .unwrap()
+        .parse(text, entities, chat.compatibility)
+        .ok_or(Error::ShouldNotHandle)?
+        .with_locale(chat.locale);
+
+    let mut formatter = parser.try_as_formatter()?.ok_or(Error::ShouldNotHandle)?;
+
+    // Resolve `@username` mentions of users the bot has seen, in the template and the receivers.
+    let mentioned = formatter.mentioned_usernames();
+    let users = users::lookup(
+        &pool,
+        mentioned.iter().map(String::as_str).chain(
+            parser
+                .mentions()
+                .iter()
+                .filter_map(Segment::mentioned_username),
+        ),
+    )
+    .await;
+
+    // Who sent the command a replied bot message answered, so that they are hit instead of the bot.
+    let command_sender = match msg.reply_to_message() {
+        Some(reply) if reply.from.as_ref() == Some(bot_user) => {
+            match booking.reverse_lookup(reply.into()).await {
+                Some(command) => users::get(&pool, command.sender).await,
+                None => None,
             }
+        }
+        _ => None,
+    };
+
+    let involved = involved_users(bot_user, command_sender.as_ref(), msg, users.values());
+    let names = settings::names(&pool, chat.name_style, &involved).await;
+
+    let resolve = |username: &str| {
+        users
+            .get(&username.to_lowercase())
+            .map(|user| names.segment(user))
+    };
+    formatter.resolve_mentions(resolve);
+    let mentions: Vec<_> = parser
+        .mentions()
+        .iter()
+        .map(|segment| {
+            segment.mentioned_username().and_then(resolve).map_or_else(
+                || segment.clone(),
+                |resolved| segment.clone().resolve_mention(resolved),
+            )
         })
-    }
-    .ok_or(Error::ShouldNotHandle)?;
+        .collect();
 
-    let formatter = parser.try_as_formatter()?;
+    let fmt_ctx = build_format_ctx(
+        bot_user,
+        command_sender.as_ref(),
+        msg,
+        &mentions,
+        &names,
+        chat.locale,
+        chat.timezone,
+    )?;
 
     Ok(formatter.format(&fmt_ctx)?)
 }
 
+// Everyone who may be named in the reply, so that their name settings are loaded at once.
+fn involved_users<'a>(
+    bot_user: &User,
+    command_sender: Option<&User>,
+    msg: &Message,
+    mentioned: impl Iterator<Item = &'a User>,
+) -> Vec<UserId> {
+    let mut involved: Vec<_> = msg
+        .from
+        .iter()
+        .chain([bot_user])
+        .map(|user| user.id)
+        .chain(mentioned.map(|user| user.id))
+        .chain(command_sender.map(|user| user.id))
+        .collect();
+    if let Some(reply) = msg.reply_to_message() {
+        involved.extend(reply.from.as_ref().map(|user| user.id));
+    }
+    involved
+}
+
+// The user being hit, how they are shown, and who they are to the sender.
 fn get_reply_user(
     bot_user: &User,
-    booking: &mut ReplyBooking,
+    command_sender: Option<&User>,
     message: &Message,
-) -> Option<Segment> {
+    names: &Names,
+    locale: &Locale,
+) -> Option<(User, Segment, Target)> {
     Some(if let Some(reply_msg) = message.reply_to_message() {
         let user = reply_msg.from.as_ref()?;
         if user == bot_user {
-            let cached_msg = booking.reverse_lookup(&reply_msg.try_into().ok()?);
-            cached_msg.map_or_else(
-                || user.into(),
-                |msg| {
-                    let sender = &msg.sender;
+            command_sender.map_or_else(
+                || (user.clone(), names.segment(user), Target::Bot),
+                |sender| {
                     message.from.as_ref().map_or_else(
-                        || sender.into(),
+                        || (sender.clone(), names.segment(sender), Target::Other),
                         |curr_sender| {
                             if sender == curr_sender {
-                                Segment::from_user_with_name(user.clone(), String::from("自己"))
+                                (
+                                    sender.clone(),
+                                    Segment::from_user_with_name(
+                                        user.clone(),
+                                        String::from(locale.reflexive),
+                                    ),
+                                    Target::Myself,
+                                )
                             } else {
-                                sender.into()
+                                (sender.clone(), names.segment(sender), Target::Other)
                             }
                         },
                     )
                 },
             )
+        } else if message.from.as_ref() == Some(user) {
+            (user.clone(), names.segment(user), Target::Myself)
         } else {
-            user.into()
+            (user.clone(), names.segment(user), Target::Other)
         }
     } else {
-        Segment::from_user_with_name(message.from.clone()?, String::from("自己"))
+        let sender = message.from.clone()?;
+        (
+            sender.clone(),
+            Segment::from_user_with_name(sender, String::from(locale.reflexive)),
+            Target::Myself,
+        )
     })
 }
 
 fn build_format_ctx(
     bot_user: &User,
-    booking: &mut ReplyBooking,
+    command_sender: Option<&User>,
     msg: &Message,
+    mentions: &[Segment],
+    names: &Names,
+    locale: Locale,
+    timezone: FixedOffset,
 ) -> Result<FormatContext> {
-    let sender = Segment::from_user(msg.from.clone().ok_or(Error::ShouldNotHandle)?);
-    let me = Segment::from_user_with_name(
-        msg.from.clone().ok_or(Error::ShouldNotHandle)?,
-        String::from("自己"),
-    );
-    let receiver = get_reply_user(bot_user, booking, msg).ok_or(Error::ShouldNotHandle)?;
-    Ok(FormatContext::new(sender, receiver, me))
+    let sender_user = msg.from.clone().ok_or(Error::ShouldNotHandle)?;
+    let sender = names.segment(&sender_user);
+    let me = Segment::from_user_with_name(sender_user.clone(), String::from(locale.reflexive));
+    let (receiver_user, receiver, target) =
+        get_reply_user(bot_user, command_sender, msg, names, &locale)
+            .ok_or(Error::ShouldNotHandle)?;
+
+    let reply = msg.reply_to_message().is_some();
+
+    // Users mentioned after the command are hit too, or instead of the sender themselves.
+    let (receivers, target) = match (mentions, reply) {
+        ([], _) => (vec![receiver.clone()], target),
+        (mentions, true) => (
+            [receiver.clone()]
+                .into_iter()
+                .chain(mentions.iter().cloned())
+                .collect(),
+            target,
+        ),
+        (mentions, false) => (mentions.to_vec(), Target::Other),
+    };
+
+    let date = msg.date.with_timezone(&timezone);
+    let ctx = FormatContext::new(sender, receiver, me)
+        .with_receivers(receivers)
+        .with_target(target, reply)
+        .with_locale(locale)
+        .with_var("time", Segment::plain(date.format(TIME_FORMAT).to_string()))
+        .with_var("date", Segment::plain(date.format(DATE_FORMAT).to_string()))
+        .with_var("bot", names.segment(bot_user));
+
+    // Variables that are missing when the message doesn't have them, so that fallbacks apply.
+    let optional_vars = [
+        ("chat", msg.chat.title().map(Segment::plain)),
+        (
+            "sender_username",
+            sender_user.username.as_deref().map(Segment::mention),
+        ),
+        (
+            "receiver_username",
+            receiver_user
+                .username
+                .as_deref()
+                .filter(|_| mentions.is_empty() || reply)
+                .map(Segment::mention),
+        ),
+        (
+            "link",
+            msg.reply_to_message()
+                .and_then(Message::url)
+                .map(|url| Segment {
+                    kind: hashset!(MessageEntityKind::Url),
+                    text: url.to_string(),
+                }),
+        ),
+    ];
+    let ctx = optional_vars
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .fold(ctx, |ctx, (name, value)| ctx.with_var(name, value));
+    Ok(match quote(msg) {
+        Some(quote) => ctx.with_rich_var("quote", quote),
+        None => ctx,
+    })
+}
+
+// The replied message for `{quote}`, or only the part of it the user quoted.
+fn quote(msg: &Message) -> Option<Segments> {
+    let (text, entities) = if let Some(quote) = msg.quote() {
+        (quote.text.as_str(), quote.entities.as_slice())
+    } else {
+        let reply = msg.reply_to_message()?;
+        (
+            reply.text().or_else(|| reply.caption())?,
+            reply
+                .entities()
+                .or_else(|| reply.caption_entities())
+                .unwrap_or_default(),
+        )
+    };
+    let limit = QUOTE_LIMIT.get().copied().unwrap_or(usize::MAX);
+    let quote = Segments::build(text, entities)
+        .trim()
+        .truncate_to(CharOffset(limit), "…");
+    // Quoting a message shouldn't ping everyone it mentioned again.
+    Some(Segments::from(
+        quote
+            .iter()
+            .cloned()
+            .map(|segment| Filter::Plain.apply(segment)),
+    ))
 }
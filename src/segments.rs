@@ -1,11 +1,15 @@
 use std::borrow::Borrow;
+use std::cmp::Reverse;
 use std::collections::{Bound, HashMap, HashSet, VecDeque};
-use std::ops::{Deref, DerefMut, RangeBounds};
+use std::ops::{Deref, DerefMut, Range, RangeBounds};
 
 use maplit::hashset;
 use ranges::Ranges;
 use teloxide::types::{MessageEntity, MessageEntityKind, User};
 
+use crate::offset::{grapheme_boundary, TextOffset};
+
+/// A run of text sharing the same set of entity kinds.
 #[derive(Debug, Eq, PartialEq, Clone, Default)]
 pub struct Segment {
     pub kind: HashSet<MessageEntityKind>,
@@ -19,21 +23,49 @@ impl Segment {
             text: String::from(" "),
         }
     }
+    pub fn plain(text: impl Into<String>) -> Self {
+        Self {
+            kind: HashSet::new(),
+            text: text.into(),
+        }
+    }
+    /// A mention of `@username`.
+    pub fn mention(username: &str) -> Self {
+        Self {
+            kind: hashset!(MessageEntityKind::Mention),
+            text: format!("@{username}"),
+        }
+    }
+    /// The username of an `@username` mention, without the `@`.
+    pub fn mentioned_username(&self) -> Option<&str> {
+        self.kind
+            .contains(&MessageEntityKind::Mention)
+            .then(|| self.text.strip_prefix('@'))
+            .flatten()
+    }
+    /// Replaces an `@username` mention with `resolved`, e.g. a mention of the user by name,
+    /// keeping other formatting.
+    pub fn resolve_mention(self, mut resolved: Self) -> Self {
+        resolved.kind.extend(
+            self.kind
+                .into_iter()
+                .filter(|kind| *kind != MessageEntityKind::Mention),
+        );
+        resolved
+    }
     pub fn from_user_with_name(user: User, name: String) -> Self {
         Self {
             text: name,
             kind: hashset!(MessageEntityKind::TextMention { user }),
         }
     }
+    /// Mentions `user` by the name `style` picks.
+    pub fn from_user_styled(user: User, style: NameStyle, nickname: Option<&str>) -> Self {
+        let name = style.name(&user, nickname);
+        Self::from_user_with_name(user, name)
+    }
     pub fn from_user(user: User) -> Self {
-        Self::from_user_with_name(
-            user.clone(),
-            if let Some(last_name) = &user.last_name {
-                format!("{} {}", user.first_name, last_name)
-            } else {
-                user.first_name
-            },
-        )
+        Self::from_user_styled(user, NameStyle::Full, None)
     }
 }
 
@@ -43,6 +75,51 @@ impl<T: Borrow<User>> From<T> for Segment {
     }
 }
 
+/// Which name of a user is shown when mentioning them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default)]
+pub enum NameStyle {
+    /// First name only.
+    First,
+    /// First and last name.
+    #[default]
+    Full,
+    /// `@username`, or the full name if the user has none.
+    Username,
+    /// A nickname the user picked, or the full name if they haven't.
+    Nickname,
+}
+
+impl NameStyle {
+    pub const ALL: &'static [Self] = &[Self::First, Self::Full, Self::Username, Self::Nickname];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::First => "first",
+            Self::Full => "full",
+            Self::Username => "username",
+            Self::Nickname => "nickname",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|style| style.as_str().eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    /// The name of `user` in this style.
+    pub fn name(self, user: &User, nickname: Option<&str>) -> String {
+        match (self, &user.username, nickname) {
+            (Self::First, _, _) => user.first_name.clone(),
+            (Self::Username, Some(username), _) => format!("@{username}"),
+            (Self::Nickname, _, Some(nickname)) => nickname.to_string(),
+            _ => user.full_name(),
+        }
+    }
+}
+
+/// Rich text as a sequence of [`Segment`]s.
 #[derive(Debug, Eq, PartialEq, Clone, Default)]
 pub struct Segments {
     data: VecDeque<Segment>,
@@ -77,6 +154,7 @@ impl DerefMut for Segments {
 }
 
 impl Segments {
+    /// Splits `text` at the boundaries of `entities`, whose offsets are in UTF-16 code units.
     pub fn build(text: &str, entities: &[MessageEntity]) -> Self {
         let text_utf16: Vec<_> = text.encode_utf16().collect();
 
@@ -138,26 +216,71 @@ impl Segments {
         Self { data: segments }
     }
 
-    pub fn drain_head(mut self, length: usize) -> Option<Self> {
-        let mut to_drain = length;
-        while to_drain > 0 && !self.data.is_empty() {
-            let front = self.data.front_mut().unwrap();
-            let text = &front.text;
-            let data_len = text.chars().count();
-            if data_len >= to_drain {
-                front.text = front.text.chars().skip(to_drain).collect();
-                to_drain = 0;
-            } else {
-                self.data.pop_front();
-                to_drain -= data_len;
+    /// Splits the segments at `at`, or returns `None` if they are not that long.
+    ///
+    /// `at` is rounded down to the start of the grapheme cluster it falls in, so that emoji
+    /// sequences and combining marks are never cut in half.
+    pub fn split_at<O: TextOffset>(self, at: O) -> Option<(Self, Self)> {
+        let text = self.text();
+        let at = grapheme_boundary(&text, at.byte_index(&text)?);
+        Some(self.split_at_byte(at))
+    }
+
+    /// The segments within `range`, which is rounded like in [`split_at`](Self::split_at).
+    pub fn slice<O: TextOffset>(&self, range: Range<O>) -> Option<Self> {
+        let text = self.text();
+        let start = grapheme_boundary(&text, range.start.byte_index(&text)?);
+        let end = grapheme_boundary(&text, range.end.byte_index(&text)?);
+        if start > end {
+            return None;
+        }
+        let (head, _) = self.clone().split_at_byte(end);
+        Some(head.split_at_byte(start).1)
+    }
+
+    /// Drops the first `length` units, or returns `None` if there are not that many.
+    ///
+    /// Like [`split_at`](Self::split_at), grapheme clusters are never cut in half.
+    pub fn drain_head<O: TextOffset>(self, length: O) -> Option<Self> {
+        self.split_at(length).map(|(_, tail)| tail)
+    }
+
+    /// Keeps the first `length` units, followed by `marker` if anything was cut.
+    ///
+    /// Like [`split_at`](Self::split_at), grapheme clusters are never cut in half.
+    pub fn truncate_to<O: TextOffset>(self, length: O, marker: &str) -> Self {
+        let text = self.text();
+        match length.byte_index(&text) {
+            Some(at) if at < text.len() => {
+                let (mut head, _) = self.split_at_byte(grapheme_boundary(&text, at));
+                head.push_back(Segment::plain(marker));
+                head
             }
+            _ => self,
         }
+    }
 
-        if to_drain == 0 {
-            Some(self)
-        } else {
-            None
+    // `at` is a byte index into `text()`, on a char boundary.
+    fn split_at_byte(self, at: usize) -> (Self, Self) {
+        let mut head = VecDeque::new();
+        let mut tail = VecDeque::new();
+        let mut offset = 0;
+        for mut segment in self.data {
+            let len = segment.text.len();
+            if offset + len <= at {
+                head.push_back(segment);
+            } else if offset >= at {
+                tail.push_back(segment);
+            } else {
+                tail.push_back(Segment {
+                    kind: segment.kind.clone(),
+                    text: segment.text.split_off(at - offset),
+                });
+                head.push_back(segment);
+            }
+            offset += len;
         }
+        (Self { data: head }, Self { data: tail })
     }
 
     pub fn trim_start(mut self) -> Self {
@@ -190,6 +313,13 @@ impl Segments {
         self.trim_start().trim_end()
     }
 
+    pub fn utf16_len(&self) -> usize {
+        self.data
+            .iter()
+            .map(|segment| segment.text.encode_utf16().count())
+            .sum()
+    }
+
     pub fn text(&self) -> String {
         self.data.iter().fold(String::new(), |mut base, segment| {
             base.push_str(segment.text.as_str());
@@ -197,6 +327,24 @@ impl Segments {
         })
     }
 
+    /// Merges adjacent segments with the same formatting and drops empty ones.
+    pub fn normalize(self) -> Self {
+        let mut data: VecDeque<Segment> = VecDeque::with_capacity(self.data.len());
+        for segment in self.data {
+            if segment.text.is_empty() {
+                continue;
+            }
+            match data.back_mut() {
+                Some(back) if back.kind == segment.kind => back.text.push_str(&segment.text),
+                _ => data.push_back(segment),
+            }
+        }
+        Self { data }
+    }
+
+    /// Converts the segments back to Telegram entities, merging adjacent runs of the same kind.
+    ///
+    /// Entities are sorted by offset, outer ones first, so the output is stable.
     pub fn entities(&self) -> Vec<MessageEntity> {
         let mut offset: usize = 0;
         let mut entity_buckets: HashMap<MessageEntityKind, Ranges<usize>> = HashMap::new();
@@ -214,7 +362,7 @@ impl Segments {
             offset += length;
         }
 
-        entity_buckets
+        let mut entities: Vec<_> = entity_buckets
             .into_iter()
             .flat_map(|(kind, ranges)| {
                 ranges
@@ -227,7 +375,16 @@ impl Segments {
                     })
                     .collect::<Vec<_>>()
             })
-            .collect()
+            .collect();
+        // `MessageEntityKind` has no order of its own, so ties are broken by its debug form.
+        entities.sort_by_cached_key(|entity| {
+            (
+                entity.offset,
+                Reverse(entity.length),
+                format!("{:?}", entity.kind),
+            )
+        });
+        entities
     }
 }
 
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use chrono::FixedOffset;
+use hithit_bot::locale::Locale;
+use hithit_bot::segments::{NameStyle, Segment};
+use sqlx::PgPool;
+use teloxide::types::{ChatId, User, UserId};
+
+pub async fn locale(pool: &PgPool, chat_id: ChatId) -> Locale {
+    sqlx::query!("SELECT locale FROM chat_locale WHERE id = $1", chat_id.0)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|row| Locale::from_name(&row.locale))
+        .unwrap_or_default()
+}
+
+/// Timezone used for `{time}` and `{date}`, UTC by default.
+pub async fn timezone(pool: &PgPool, chat_id: ChatId) -> FixedOffset {
+    sqlx::query!(
+        "SELECT utc_offset FROM chat_timezone WHERE id = $1",
+        chat_id.0
+    )
+    .fetch_optional(pool)
+    .await
+    .ok()
+    .flatten()
+    .and_then(|row| FixedOffset::east_opt(row.utc_offset))
+    .unwrap_or_else(utc)
+}
+
+/// The settings of a chat that rendering a command needs.
+#[derive(Debug, Clone, Copy)]
+pub struct ChatSettings {
+    /// Whether naive mode is limited to `/explain`, see `/compatibility`.
+    pub compatibility: bool,
+    pub locale: Locale,
+    pub timezone: FixedOffset,
+    pub name_style: NameStyle,
+}
+
+/// Loads the settings of a chat at once, falling back to the defaults.
+pub async fn chat(pool: &PgPool, chat_id: ChatId) -> ChatSettings {
+    let row = sqlx::query!(
+        r#"SELECT cm.id IS NOT NULL AS "compatibility!", l.locale AS "locale?",
+            t.utc_offset AS "utc_offset?", s.style AS "style?"
+        FROM (SELECT $1::BIGINT AS id) AS c
+        LEFT JOIN compatibility cm ON cm.id = c.id
+        LEFT JOIN chat_locale l ON l.id = c.id
+        LEFT JOIN chat_timezone t ON t.id = c.id
+        LEFT JOIN chat_name_style s ON s.id = c.id"#,
+        chat_id.0
+    )
+    .fetch_one(pool)
+    .await
+    .ok();
+    let row = row.as_ref();
+    ChatSettings {
+        compatibility: row.is_some_and(|row| row.compatibility),
+        locale: row
+            .and_then(|row| row.locale.as_deref())
+            .and_then(Locale::from_name)
+            .unwrap_or_default(),
+        timezone: row
+            .and_then(|row| row.utc_offset)
+            .and_then(FixedOffset::east_opt)
+            .unwrap_or_else(utc),
+        name_style: row
+            .and_then(|row| row.style.as_deref())
+            .and_then(NameStyle::from_name)
+            .unwrap_or_default(),
+    }
+}
+
+pub fn utc() -> FixedOffset {
+    FixedOffset::east_opt(0).expect("UTC is a valid offset")
+}
+
+/// How users are named in a chat: the chat's style, unless a user picked their own.
+#[derive(Debug, Clone, Default)]
+pub struct Names {
+    style: NameStyle,
+    users: HashMap<UserId, (Option<NameStyle>, Option<String>)>,
+}
+
+impl Names {
+    pub fn style_of(&self, user: UserId) -> NameStyle {
+        self.users
+            .get(&user)
+            .and_then(|(style, _)| *style)
+            .unwrap_or(self.style)
+    }
+    pub fn segment(&self, user: &User) -> Segment {
+        let (style, nickname) = self
+            .users
+            .get(&user.id)
+            .map_or((None, None), |(style, nickname)| {
+                (*style, nickname.as_deref())
+            });
+        Segment::from_user_styled(user.clone(), style.unwrap_or(self.style), nickname)
+    }
+}
+
+pub async fn chat_name_style(pool: &PgPool, chat_id: ChatId) -> NameStyle {
+    sqlx::query!("SELECT style FROM chat_name_style WHERE id = $1", chat_id.0)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|row| NameStyle::from_name(&row.style))
+        .unwrap_or_default()
+}
+
+/// Loads the name settings of `users`, who are named in `style` unless they picked their own.
+pub async fn names(pool: &PgPool, style: NameStyle, users: &[UserId]) -> Names {
+    let ids: Vec<_> = users.iter().map(|id| id.0.cast_signed()).collect();
+    let users = sqlx::query!(
+        "SELECT id, style, nickname FROM user_name WHERE id = ANY($1)",
+        &ids
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default()
+    .into_iter()
+    .map(|row| {
+        (
+            UserId(row.id.cast_unsigned()),
+            (
+                row.style.as_deref().and_then(NameStyle::from_name),
+                row.nickname,
+            ),
+        )
+    })
+    .collect();
+    Names { style, users }
+}
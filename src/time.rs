@@ -0,0 +1,44 @@
+/// Parses a timezone for `{time}` and `{date}`, such as `+08:00`, `UTC+8` or `-0530`, into
+/// seconds east of UTC.
+///
+/// Offsets beyond ±14 hours don't exist and are rejected.
+pub fn parse_utc_offset(text: &str) -> Option<i32> {
+    const MAX_OFFSET: i32 = 14 * 3600;
+
+    let text = text.trim();
+    let text = ["UTC", "GMT"]
+        .iter()
+        .find_map(|prefix| {
+            text.get(..prefix.len())
+                .filter(|head| head.eq_ignore_ascii_case(prefix))
+                .map(|_| &text[prefix.len()..])
+        })
+        .unwrap_or(text);
+    if text.is_empty() {
+        return Some(0);
+    }
+
+    let (sign, rest) = match text.as_bytes()[0] {
+        b'+' => (1, &text[1..]),
+        b'-' => (-1, &text[1..]),
+        _ => (1, text),
+    };
+    // Only digits are left after the sign, so that `--5` or `+-5` aren't read as negated twice.
+    if rest.is_empty() || !rest.bytes().all(|b| b.is_ascii_digit() || b == b':') {
+        return None;
+    }
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if rest.len() > 2 => rest.split_at(rest.len() - 2),
+        None => (rest, "0"),
+    };
+    let hours: i32 = hours.parse().ok()?;
+    let minutes: i32 = minutes.parse().ok()?;
+    if !(0..60).contains(&minutes) {
+        return None;
+    }
+    let offset = sign * (hours.checked_mul(3600)? + minutes * 60);
+    (-MAX_OFFSET..=MAX_OFFSET)
+        .contains(&offset)
+        .then_some(offset)
+}
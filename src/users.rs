@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use sqlx::PgPool;
+use teloxide::types::{Update, UpdateKind, User, UserId};
+use tracing::warn;
+
+/// Remembers the users in an update, so that `@username` mentions of them can be resolved later.
+///
+/// This runs in the background, so that handlers don't wait for it.
+pub fn record_update(update: Update, pool: PgPool) {
+    let reply_sender = match &update.kind {
+        UpdateKind::Message(msg) | UpdateKind::EditedMessage(msg) => {
+            msg.reply_to_message().and_then(|reply| reply.from.as_ref())
+        }
+        _ => None,
+    };
+    let mut users: Vec<_> = update
+        .from()
+        .into_iter()
+        .chain(reply_sender)
+        .cloned()
+        .collect();
+    users.dedup_by_key(|user| user.id);
+    if users.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Err(e) = record(&pool, &users).await {
+            warn!(?e, "failed to record users");
+        }
+    });
+}
+
+/// Remembers `users`, or updates what is known about them. Rows that didn't change are left alone.
+pub async fn record(pool: &PgPool, users: &[User]) -> sqlx::Result<()> {
+    let ids: Vec<_> = users.iter().map(|user| user.id.0.cast_signed()).collect();
+    let is_bot: Vec<_> = users.iter().map(|user| user.is_bot).collect();
+    let first_names: Vec<_> = users.iter().map(|user| user.first_name.clone()).collect();
+    let last_names: Vec<_> = users.iter().map(|user| user.last_name.clone()).collect();
+    let usernames: Vec<_> = users.iter().map(|user| user.username.clone()).collect();
+    sqlx::query!(
+        "INSERT INTO users (id, is_bot, first_name, last_name, username, updated_at) \
+         SELECT *, now() FROM UNNEST($1::BIGINT[], $2::BOOLEAN[], $3::TEXT[], $4::TEXT[], $5::TEXT[]) \
+         ON CONFLICT (id) DO UPDATE SET is_bot = EXCLUDED.is_bot, first_name = EXCLUDED.first_name, \
+         last_name = EXCLUDED.last_name, username = EXCLUDED.username, updated_at = now() \
+         WHERE (users.is_bot, users.first_name, users.last_name, users.username) \
+         IS DISTINCT FROM (EXCLUDED.is_bot, EXCLUDED.first_name, EXCLUDED.last_name, EXCLUDED.username)",
+        &ids,
+        &is_bot,
+        &first_names,
+        &last_names as &[Option<String>],
+        &usernames as &[Option<String>]
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Looks up users by username, ignoring case. If a username changed hands, the user who took it
+/// most recently wins.
+pub async fn lookup(
+    pool: &PgPool,
+    usernames: impl IntoIterator<Item = &str>,
+) -> HashMap<String, User> {
+    let usernames: Vec<_> = usernames.into_iter().map(str::to_lowercase).collect();
+    if usernames.is_empty() {
+        return HashMap::new();
+    }
+
+    let rows = sqlx::query!(
+        "SELECT DISTINCT ON (lower(username)) id, is_bot, first_name, last_name, username \
+         FROM users WHERE lower(username) = ANY($1) ORDER BY lower(username), updated_at DESC",
+        &usernames
+    )
+    .fetch_all(pool)
+    .await
+    .unwrap_or_else(|e| {
+        warn!(?e, "failed to look up users");
+        vec![]
+    });
+    rows.into_iter()
+        .filter_map(|row| {
+            Some((
+                row.username.as_deref()?.to_lowercase(),
+                user(
+                    row.id,
+                    row.is_bot,
+                    row.first_name,
+                    row.last_name,
+                    row.username,
+                ),
+            ))
+        })
+        .collect()
+}
+
+/// Looks up a user the bot has seen by id.
+pub async fn get(pool: &PgPool, id: UserId) -> Option<User> {
+    let row = sqlx::query!(
+        "SELECT id, is_bot, first_name, last_name, username FROM users WHERE id = $1",
+        id.0.cast_signed()
+    )
+    .fetch_optional(pool)
+    .await
+    .unwrap_or_else(|e| {
+        warn!(?e, %id, "failed to look up user");
+        None
+    })?;
+    Some(user(
+        row.id,
+        row.is_bot,
+        row.first_name,
+        row.last_name,
+        row.username,
+    ))
+}
+
+/// A user as stored in the `users` table.
+pub fn user(
+    id: i64,
+    is_bot: bool,
+    first_name: String,
+    last_name: Option<String>,
+    username: Option<String>,
+) -> User {
+    User {
+        id: UserId(id.cast_unsigned()),
+        is_bot,
+        first_name,
+        last_name,
+        username,
+        language_code: None,
+        is_premium: false,
+        added_to_attachment_menu: false,
+    }
+}
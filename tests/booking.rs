@@ -0,0 +1,149 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hithit_bot::booking::{Booked, BookingStore, MessageKey};
+use teloxide::types::{ChatId, MessageId, UserId};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+fn booked(chat: i64, message: i32, sender: u64) -> Booked {
+    Booked {
+        message: MessageKey {
+            chat_id: ChatId(chat),
+            message_id: MessageId(message),
+        },
+        sender: UserId(sender),
+    }
+}
+
+#[test]
+fn lookups_go_both_ways() {
+    let store = BookingStore::new(16, HOUR);
+    let (command, reply) = (booked(-1, 10, 1), booked(-1, 11, 0));
+    store.book(command, reply);
+
+    assert_eq!(store.forward_lookup(command.message), Some(reply));
+    assert_eq!(store.reverse_lookup(reply.message), Some(command));
+    assert_eq!(store.reverse_lookup(booked(-2, 11, 0).message), None);
+
+    let stats = store.stats();
+    assert_eq!((stats.len, stats.hits, stats.misses), (1, 2, 1));
+}
+
+#[test]
+fn rebooking_replaces_the_reply() {
+    let store = BookingStore::new(16, HOUR);
+    let command = booked(-1, 10, 1);
+    store.book(command, booked(-1, 11, 0));
+    store.book(command, booked(-1, 12, 0));
+
+    assert_eq!(
+        store.forward_lookup(command.message),
+        Some(booked(-1, 12, 0))
+    );
+    assert_eq!(store.reverse_lookup(booked(-1, 11, 0).message), None);
+    assert_eq!(store.stats().len, 1);
+}
+
+#[test]
+fn least_recently_used_bookings_are_evicted() {
+    let store = BookingStore::with_shards(2, HOUR, 1);
+    store.book(booked(-1, 1, 1), booked(-1, 2, 0));
+    store.book(booked(-1, 3, 1), booked(-1, 4, 0));
+    assert!(store.forward_lookup(booked(-1, 1, 1).message).is_some());
+    store.book(booked(-1, 5, 1), booked(-1, 6, 0));
+
+    assert!(store.forward_lookup(booked(-1, 3, 1).message).is_none());
+    assert!(store.reverse_lookup(booked(-1, 4, 0).message).is_none());
+    assert!(store.forward_lookup(booked(-1, 1, 1).message).is_some());
+    assert_eq!(store.stats().evictions, 1);
+}
+
+#[test]
+fn old_bookings_expire() {
+    let store = BookingStore::new(16, HOUR);
+    let long_ago = Instant::now().checked_sub(2 * HOUR).unwrap();
+    store.book_at(booked(-1, 1, 1), booked(-1, 2, 0), long_ago);
+    store.book(booked(-1, 3, 1), booked(-1, 4, 0));
+
+    assert_eq!(store.reverse_lookup(booked(-1, 2, 0).message), None);
+    store.remove_expired();
+    let stats = store.stats();
+    assert_eq!((stats.len, stats.evictions), (1, 1));
+}
+
+#[test]
+fn forgotten_bookings_are_gone() {
+    let store = BookingStore::new(16, HOUR);
+    let (command, reply) = (booked(-1, 10, 1), booked(-1, 11, 0));
+    store.book(command, reply);
+    store.forget(command.message);
+
+    assert_eq!(store.forward_lookup(command.message), None);
+    assert_eq!(store.reverse_lookup(reply.message), None);
+}
+
+#[test]
+fn chats_are_booked_concurrently() {
+    // Enough room for every booking even if they all end up in the same shard.
+    let store = BookingStore::with_shards(8 * 800, HOUR, 8);
+    thread::scope(|scope| {
+        for chat in 0..8 {
+            let store = &store;
+            scope.spawn(move || {
+                for message in 0..100 {
+                    store.book(
+                        booked(chat, message * 2, 1),
+                        booked(chat, message * 2 + 1, 0),
+                    );
+                }
+            });
+        }
+    });
+
+    for chat in 0..8 {
+        for message in 0..100 {
+            let reply = booked(chat, message * 2 + 1, 0).message;
+            assert_eq!(
+                store.reverse_lookup(reply),
+                Some(booked(chat, message * 2, 1))
+            );
+        }
+    }
+}
+
+#[test]
+fn one_chat_can_use_every_shard() {
+    let store = BookingStore::with_shards(64, HOUR, 8);
+    for message in 0..32 {
+        store.book(booked(-1, message * 2, 1), booked(-1, message * 2 + 1, 0));
+    }
+
+    let stats = store.stats();
+    assert_eq!((stats.len, stats.evictions), (32, 0));
+    for message in 0..32 {
+        assert!(store
+            .reverse_lookup(booked(-1, message * 2 + 1, 0).message)
+            .is_some());
+    }
+}
+
+#[test]
+fn forgetting_while_booking_leaves_no_replies_behind() {
+    let store = BookingStore::with_shards(64, HOUR, 8);
+    let command = booked(-1, 1, 1);
+    thread::scope(|scope| {
+        for thread in 0..4 {
+            let store = &store;
+            scope.spawn(move || {
+                for message in 0..1000 {
+                    store.book(command, booked(-1, 2 + thread * 1000 + message, 0));
+                    store.forget(command.message);
+                }
+            });
+        }
+    });
+
+    let stats = store.stats();
+    assert_eq!((stats.len, stats.replies), (0, 0));
+}
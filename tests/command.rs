@@ -0,0 +1,43 @@
+use hithit_bot::command::CommandParser;
+use hithit_bot::segments::Segment;
+use teloxide::types::{MessageEntity, MessageEntityKind};
+
+#[test]
+fn trailing_mentions_are_taken() {
+    let entities = [
+        MessageEntity::new(MessageEntityKind::Mention, 3, 6),
+        MessageEntity::new(MessageEntityKind::Mention, 10, 4),
+    ];
+    let parser = CommandParser::new('^')
+        .parse("/打 @alice @bob", &entities, false)
+        .unwrap();
+    let mentions: Vec<_> = parser.mentions().iter().map(|s| s.text.as_str()).collect();
+    assert_eq!(mentions, ["@alice", "@bob"]);
+}
+
+#[test]
+fn inner_mentions_are_kept() {
+    let entities = [MessageEntity::new(MessageEntityKind::Mention, 3, 6)];
+    let parser = CommandParser::new('^')
+        .parse("/打 @alice 的头", &entities, false)
+        .unwrap();
+    assert_eq!(parser.mentions(), &[] as &[Segment]);
+}
+
+#[test]
+fn templates_keep_mentions_unless_they_use_receivers() {
+    let entities = [MessageEntity::new(MessageEntityKind::Mention, 12, 6)];
+    let parser = CommandParser::new('^')
+        .parse("/{s} 打了 {r} @alice", &entities, false)
+        .unwrap();
+    let mentions: Vec<_> = parser.mentions().iter().map(|s| s.text.as_str()).collect();
+    assert_eq!(mentions, ["@alice"]);
+
+    let entities = [MessageEntity::new(MessageEntityKind::Mention, 8, 6)];
+    let parser = CommandParser::new('^')
+        .parse("/{s} 打了 @alice", &entities, false)
+        .unwrap();
+    assert_eq!(parser.mentions(), &[] as &[Segment]);
+    let formatter = parser.try_as_formatter().unwrap().unwrap();
+    assert!(formatter.mentioned_usernames().contains("alice"));
+}
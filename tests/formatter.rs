@@ -0,0 +1,205 @@
+use hithit_bot::error::ExportedError;
+use hithit_bot::formatter::{FormatContext, Target};
+use hithit_bot::parser::{ParseError, ParseErrorKind, Parser};
+use hithit_bot::segments::{Segment, Segments};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use teloxide::types::{MessageEntity, MessageEntityKind, User, UserId};
+
+fn bob() -> User {
+    User {
+        id: UserId(2),
+        is_bot: false,
+        first_name: String::from("Bob"),
+        last_name: Some(String::from("Smith")),
+        username: None,
+        language_code: None,
+        is_premium: false,
+        added_to_attachment_menu: false,
+    }
+}
+
+fn ctx() -> FormatContext {
+    FormatContext::new(
+        Segment::plain("alice"),
+        Segment::from(bob()),
+        Segment::plain("自己"),
+    )
+    .with_rng(StdRng::seed_from_u64(0))
+}
+
+fn render(
+    text: &str,
+    entities: &[MessageEntity],
+    ctx: &FormatContext,
+) -> Result<Segments, ExportedError> {
+    let formatter = Parser::new(Segments::build(text, entities), true)
+        .try_as_formatter()?
+        .expect("message is a template");
+    Ok(formatter.format(ctx)?)
+}
+
+fn parse_error(text: &str) -> ParseError {
+    match render(text, &[], &ctx()) {
+        Err(ExportedError::Parse(e)) => *e,
+        other => panic!("expected a parse error, got {other:?}"),
+    }
+}
+
+#[test]
+fn filters_change_the_value() {
+    let output = render("{s|upper} 打了 {r|first}", &[], &ctx()).unwrap();
+    assert_eq!(output.text(), "ALICE 打了 Bob！");
+    assert_eq!(
+        output.entities(),
+        [MessageEntity::new(
+            MessageEntityKind::TextMention { user: bob() },
+            9,
+            3
+        )]
+    );
+
+    let output = render("{r|plain}", &[], &ctx()).unwrap();
+    assert_eq!(output.text(), "Bob Smith！");
+    assert!(output.entities().is_empty());
+}
+
+#[test]
+fn unknown_filters_are_errors() {
+    let error = parse_error("{r|uppr}");
+    assert_eq!(
+        error.kind,
+        ParseErrorKind::UnknownFilter(String::from("uppr"))
+    );
+    assert_eq!(error.span, 3..7);
+}
+
+#[test]
+fn filters_are_not_choices() {
+    let formatter = Parser::new(Segments::build("{hit|first}", &[]), true)
+        .try_as_formatter()
+        .unwrap()
+        .unwrap();
+    assert!(formatter.named_holes().contains("hit"));
+    assert!(!formatter.has_control_flow());
+}
+
+#[test]
+fn words_without_filters_are_choices() {
+    let words = ["hit", "kick"];
+    for seed in 0..16 {
+        let picked = StdRng::seed_from_u64(seed).random_range(0..words.len());
+        let ctx = ctx().with_rng(StdRng::seed_from_u64(seed));
+        let output = render("{s} {hit|kick}s {r|first}", &[], &ctx).unwrap();
+        assert_eq!(output.text(), format!("alice {}s Bob！", words[picked]));
+    }
+}
+
+#[test]
+fn conditionals_follow_the_target() {
+    let template = "{?self}{s} 抱了抱 自己{:}{s} 打了 {r}{/}";
+    let output = render(template, &[], &ctx().with_target(Target::Myself, false)).unwrap();
+    assert_eq!(output.text(), "alice 抱了抱 自己！");
+    let output = render(template, &[], &ctx().with_target(Target::Other, true)).unwrap();
+    assert_eq!(output.text(), "alice 打了 Bob Smith！");
+}
+
+#[test]
+fn choices_are_picked_by_the_rng() {
+    let verbs = ["打", "踢", "摸"];
+    for seed in 0..16 {
+        let picked = StdRng::seed_from_u64(seed).random_range(0..verbs.len());
+        let ctx = ctx().with_rng(StdRng::seed_from_u64(seed));
+        let output = render("{s} {打|踢|摸}了 {r|first}", &[], &ctx).unwrap();
+        assert_eq!(output.text(), format!("alice {}了 Bob！", verbs[picked]));
+    }
+}
+
+#[test]
+fn choices_may_contain_holes() {
+    let output = render("{{s}|{s}}", &[], &ctx()).unwrap();
+    assert_eq!(output.text(), "alice！");
+}
+
+#[test]
+fn fallbacks_fill_missing_holes() {
+    let output = render("{s} 打了 {target:路人}", &[], &ctx()).unwrap();
+    assert_eq!(output.text(), "alice 打了 路人！");
+
+    let error = render("{s} 打了 {target}", &[], &ctx()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "format error: key target not found at position 7"
+    );
+    assert_eq!(error.span(), 7..15);
+}
+
+#[test]
+fn unclosed_braces_are_located() {
+    let error = parse_error("{s} 打了 {");
+    assert_eq!(error.kind, ParseErrorKind::UnclosedBrace);
+    assert_eq!(error.to_string(), "unclosed `{` at position 7");
+
+    // Positions are in UTF-16 code units, as Telegram counts them.
+    assert_eq!(parse_error("😀{s} {").span.start, 6);
+}
+
+#[test]
+fn invalid_braces_are_located() {
+    let error = parse_error("{s} {foo bar}");
+    assert_eq!(
+        error.kind,
+        ParseErrorKind::InvalidBrace(String::from("{foo bar}"))
+    );
+    assert_eq!(error.span, 4..13);
+}
+
+#[test]
+fn emoticons_in_plain_commands_are_kept() {
+    let output = render("抱抱 {>_<}", &[], &ctx()).unwrap();
+    assert!(output.text().contains("{>_<}"), "{}", output.text());
+}
+
+#[test]
+fn block_errors_are_located() {
+    let error = parse_error("{s} {?self}抱");
+    assert_eq!(
+        error.kind,
+        ParseErrorKind::UnclosedBlock(String::from("{?self}"))
+    );
+    assert_eq!(error.span, 4..11);
+
+    let error = parse_error("{s}{/}");
+    assert_eq!(error.kind, ParseErrorKind::UnmatchedClose);
+    assert_eq!(error.span, 3..6);
+
+    let error = parse_error("{?mars}{/}");
+    assert_eq!(
+        error.kind,
+        ParseErrorKind::UnknownCondition(String::from("mars"))
+    );
+    assert_eq!(error.span, 2..6);
+}
+
+#[test]
+fn holes_may_cross_segments() {
+    // `{` is bold and `r}` is not, so the hole is not bold.
+    let entities = [MessageEntity::new(MessageEntityKind::Bold, 4, 1)];
+    let output = render("{s} {r}", &entities, &ctx()).unwrap();
+    assert_eq!(output.text(), "alice Bob Smith！");
+    assert_eq!(
+        output.entities(),
+        [MessageEntity::new(
+            MessageEntityKind::TextMention { user: bob() },
+            6,
+            9
+        )]
+    );
+
+    // A hole that is bold as a whole stays bold.
+    let entities = [MessageEntity::new(MessageEntityKind::Bold, 4, 3)];
+    let output = render("{s} {r}", &entities, &ctx()).unwrap();
+    assert!(output
+        .entities()
+        .contains(&MessageEntity::new(MessageEntityKind::Bold, 6, 9)));
+}
@@ -0,0 +1,31 @@
+use hithit_bot::inflect::english_past;
+
+#[test]
+fn english_past_tense() {
+    let cases = [
+        ("slap", "slapped"),
+        ("pat", "patted"),
+        ("quiz", "quizzed"),
+        ("kidnap", "kidnapped"),
+        ("visit", "visited"),
+        ("open", "opened"),
+        ("boop", "booped"),
+        ("kiss", "kissed"),
+        ("fix", "fixed"),
+        ("play", "played"),
+        ("poke", "poked"),
+        ("try", "tried"),
+        ("panic", "panicked"),
+        ("hit", "hit"),
+        ("throw", "threw"),
+        ("go", "went"),
+        ("high-five", "high-fived"),
+        ("Slap", "Slapped"),
+        ("SLAP", "SLAPPED"),
+        ("打", "打"),
+        ("slap!", "slap!"),
+    ];
+    for (verb, past) in cases {
+        assert_eq!(english_past(verb), past, "past tense of {verb}");
+    }
+}
@@ -0,0 +1,93 @@
+use hithit_bot::limits::Limits;
+use hithit_bot::segments::{Segment, Segments};
+use maplit::hashset;
+use teloxide::types::{MessageEntityKind, User, UserId};
+
+fn bob() -> Segment {
+    Segment::from_user(User {
+        id: UserId(2),
+        is_bot: false,
+        first_name: String::from("Bob"),
+        last_name: None,
+        username: None,
+        language_code: None,
+        is_premium: false,
+        added_to_attachment_menu: false,
+    })
+}
+
+#[test]
+fn long_replies_are_cut() {
+    let segments = Segments::from([Segment::plain("😀".repeat(3000))]);
+    let limited = Limits::default().apply(segments, "…");
+    // Emoji take two code units and are never split, so one unit may be left over.
+    assert!((4095..=4096).contains(&limited.utf16_len()));
+    assert!(limited.text().ends_with("😀…"));
+}
+
+#[test]
+fn replies_with_many_entities_are_cut() {
+    let segments = Segments::from((0..150).flat_map(|i| {
+        [
+            Segment {
+                kind: hashset!(MessageEntityKind::Bold),
+                text: i.to_string(),
+            },
+            Segment::plain(" "),
+        ]
+    }));
+    let limited = Limits::default().apply(segments, "…");
+    assert_eq!(limited.entities().len(), 100);
+    assert!(limited.text().ends_with('…'));
+}
+
+#[test]
+fn pings_are_capped() {
+    let segments = Segments::from((0..5).flat_map(|_| [bob(), Segment::plain(" ")]));
+    let limited = Limits::default().with_max_pings(2).apply(segments, "…");
+    assert_eq!(limited.text(), "Bob Bob Bob Bob Bob ");
+    assert_eq!(limited.entities().len(), 2);
+}
+
+#[test]
+fn mentions_split_by_formatting_ping_once() {
+    let alice = || {
+        [
+            Segment {
+                kind: hashset!(MessageEntityKind::Mention, MessageEntityKind::Bold),
+                text: String::from("@Al"),
+            },
+            Segment {
+                kind: hashset!(MessageEntityKind::Mention),
+                text: String::from("ice"),
+            },
+            Segment::plain(" "),
+        ]
+    };
+    let segments = Segments::from(alice().into_iter().chain(alice()));
+    let limited = Limits::default().with_max_pings(1).apply(segments, "…");
+    assert_eq!(limited.text(), "@Alice @Alice ");
+    let mentions: Vec<_> = limited
+        .entities()
+        .into_iter()
+        .filter(|entity| entity.kind == MessageEntityKind::Mention)
+        .map(|entity| (entity.offset, entity.length))
+        .collect();
+    assert_eq!(mentions, [(0, 6)]);
+}
+
+#[test]
+fn text_mentions_split_by_formatting_ping_once() {
+    let mut head = bob();
+    head.text = String::from("Bo");
+    head.kind.insert(MessageEntityKind::Bold);
+    let mut tail = bob();
+    tail.text = String::from("b");
+    let limited = Limits::default()
+        .with_max_pings(1)
+        .apply(Segments::from([head, tail]), "…");
+    assert!(limited.entities().iter().any(|entity| matches!(
+        entity.kind,
+        MessageEntityKind::TextMention { .. }
+    ) && entity.length == 3));
+}
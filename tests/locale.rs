@@ -0,0 +1,29 @@
+use hithit_bot::locale::Locale;
+use hithit_bot::offset::CharOffset;
+
+#[test]
+fn aspect_marker_position() {
+    let cases = [
+        ("打", Some(1)),
+        ("打了", None),
+        ("吃过", None),
+        ("看着", None),
+        ("吃完", Some(2)),
+        ("摸摸", Some(1)),
+        ("看一看", Some(1)),
+        ("打啊", Some(1)),
+        ("打！", Some(1)),
+        ("打啊🥺", Some(1)),
+        ("打了啊🥺", None),
+        ("🥺", Some(1)),
+    ];
+    for (verb, position) in cases {
+        assert_eq!(
+            Locale::ZH_HANS.aspect_position(verb),
+            position.map(CharOffset),
+            "aspect marker in {verb}"
+        );
+    }
+    assert_eq!(Locale::YUE.aspect_position("睇紧"), None);
+    assert_eq!(Locale::EN.aspect_position("slap"), None);
+}
@@ -0,0 +1,202 @@
+use hithit_bot::markup::MarkupErrorKind;
+use hithit_bot::segments::{Segment, Segments};
+use maplit::hashset;
+use teloxide::types::{MessageEntityKind, User, UserId};
+
+fn styled(text: &str, kind: &[MessageEntityKind]) -> Segment {
+    Segment {
+        kind: kind.iter().cloned().collect(),
+        text: text.to_string(),
+    }
+}
+
+fn alice() -> User {
+    User {
+        id: UserId(1),
+        is_bot: false,
+        first_name: String::from("Alice"),
+        last_name: None,
+        username: None,
+        language_code: None,
+        is_premium: false,
+        added_to_attachment_menu: false,
+    }
+}
+
+fn sample() -> Segments {
+    use MessageEntityKind::*;
+    Segments::from([
+        Segment::from_user(alice()),
+        Segment::plain(" hit "),
+        styled("very", &[Bold]),
+        styled(" hard", &[Bold, Italic]),
+        Segment::plain(" (1 < 2 & 3.5 > 1_000) "),
+        styled("under", &[Underline]),
+        styled("lined", &[Underline, Italic]),
+        Segment::plain(" "),
+        styled(
+            "docs",
+            &[TextLink {
+                url: "https://example.com/a_(b)".parse().unwrap(),
+            }],
+        ),
+        Segment::plain(" "),
+        styled("`x` \\ *", &[Code]),
+        Segment::plain("\n"),
+        styled(
+            "fn main() {}",
+            &[Pre {
+                language: Some(String::from("rust")),
+            }],
+        ),
+    ])
+}
+
+#[test]
+fn html_round_trips() {
+    let segments = sample();
+    let html = segments.to_html();
+    assert_eq!(Segments::from_html(&html).unwrap(), segments.normalize());
+}
+
+#[test]
+fn markdown_v2_round_trips() {
+    let segments = sample();
+    let markdown = segments.to_markdown_v2();
+    assert_eq!(
+        Segments::from_markdown_v2(&markdown).unwrap(),
+        segments.normalize()
+    );
+}
+
+#[test]
+fn html_is_escaped_and_nested() {
+    use MessageEntityKind::*;
+    let segments = Segments::from([
+        styled("a", &[Bold]),
+        styled("<b>", &[Bold, Italic]),
+        Segment::plain(" & \"c\""),
+    ]);
+    assert_eq!(
+        segments.to_html(),
+        "<b>a<i>&lt;b&gt;</i></b> &amp; &quot;c&quot;"
+    );
+}
+
+#[test]
+fn markdown_v2_is_escaped() {
+    let segments = Segments::from([
+        Segment::plain("1+1=2. "),
+        styled("a`b", &[MessageEntityKind::Code]),
+    ]);
+    assert_eq!(segments.to_markdown_v2(), "1\\+1\\=2\\. `a\\`b`");
+}
+
+#[test]
+fn html_mentions_are_named_after_their_text() {
+    let segments = Segments::from_html("<a href=\"tg://user?id=1\">Alice</a>").unwrap();
+    assert_eq!(segments, Segments::from([Segment::from_user(alice())]));
+}
+
+#[test]
+fn html_accepts_aliases_and_references() {
+    let segments =
+        Segments::from_html("<strong>&#x1F600;</strong><span class=\"tg-spoiler\">&#33;</span>")
+            .unwrap();
+    assert_eq!(
+        segments,
+        Segments::from([
+            styled("😀", &[MessageEntityKind::Bold]),
+            styled("!", &[MessageEntityKind::Spoiler]),
+        ])
+    );
+}
+
+#[test]
+fn markdown_v2_blockquotes_span_lines() {
+    let segments = Segments::from_markdown_v2(">quoted\n>lines\nafter").unwrap();
+    assert_eq!(
+        segments,
+        Segments::from([
+            styled("quoted\nlines", &[MessageEntityKind::Blockquote]),
+            Segment::plain("\nafter"),
+        ])
+    );
+    assert_eq!(segments.to_markdown_v2(), ">quoted\n>lines\nafter");
+}
+
+#[test]
+fn custom_emoji_round_trip() {
+    let segments = Segments::from([styled(
+        "👍",
+        &[MessageEntityKind::CustomEmoji {
+            custom_emoji_id: String::from("5368324170671202286"),
+        }],
+    )]);
+    assert_eq!(
+        segments.to_markdown_v2(),
+        "![👍](tg://emoji?id=5368324170671202286)"
+    );
+    assert_eq!(
+        segments.to_html(),
+        "<tg-emoji emoji-id=\"5368324170671202286\">👍</tg-emoji>"
+    );
+    assert_eq!(Segments::from_html(&segments.to_html()).unwrap(), segments);
+}
+
+#[test]
+fn detected_entities_are_plain_text() {
+    let segments = Segments::from([Segment::mention("alice")]);
+    assert_eq!(segments.to_html(), "@alice");
+    assert_eq!(Segments::from_html("@alice").unwrap().entities().len(), 0);
+    assert_eq!(
+        Segments::from([Segment {
+            kind: hashset!(MessageEntityKind::Mention, MessageEntityKind::Bold),
+            text: String::from("@alice"),
+        }])
+        .to_html(),
+        "<b>@alice</b>"
+    );
+}
+
+#[test]
+fn html_errors_are_located() {
+    let error = Segments::from_html("ok <b>bold").unwrap_err();
+    assert_eq!(error.kind, MarkupErrorKind::Unclosed(String::from("<b>")));
+    assert_eq!(error.position, 3);
+
+    let error = Segments::from_html("<b><i>x</b></i>").unwrap_err();
+    assert_eq!(error.kind, MarkupErrorKind::Unclosed(String::from("<i>")));
+
+    let error = Segments::from_html("<marquee>x</marquee>").unwrap_err();
+    assert_eq!(
+        error.kind,
+        MarkupErrorKind::UnknownTag(String::from("marquee"))
+    );
+
+    let error = Segments::from_html("<a>x</a>").unwrap_err();
+    assert!(matches!(
+        error.kind,
+        MarkupErrorKind::MissingAttribute { .. }
+    ));
+
+    let error = Segments::from_html("a &nbsp; b").unwrap_err();
+    assert_eq!(
+        error.kind,
+        MarkupErrorKind::UnknownReference(String::from("nbsp"))
+    );
+}
+
+#[test]
+fn markdown_v2_errors_are_located() {
+    let error = Segments::from_markdown_v2("done.").unwrap_err();
+    assert_eq!(error.kind, MarkupErrorKind::Unescaped('.'));
+    assert_eq!(error.position, 4);
+
+    let error = Segments::from_markdown_v2("*bold _both* italic_").unwrap_err();
+    assert_eq!(error.kind, MarkupErrorKind::Unclosed(String::from("_")));
+    assert_eq!(error.position, 6);
+
+    let error = Segments::from_markdown_v2("[link](not a url)").unwrap_err();
+    assert!(matches!(error.kind, MarkupErrorKind::InvalidUrl(_)));
+}
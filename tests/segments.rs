@@ -0,0 +1,244 @@
+use std::collections::{HashMap, HashSet};
+
+use hithit_bot::offset::{CharOffset, GraphemeOffset, Utf16Offset};
+use hithit_bot::segments::Segments;
+use proptest::prelude::*;
+use teloxide::types::{MessageEntity, MessageEntityKind};
+use unicode_segmentation::UnicodeSegmentation;
+
+// Mixes ASCII, CJK, combining marks and characters outside the BMP (surrogate pairs in UTF-16).
+fn text() -> impl Strategy<Value = Vec<char>> {
+    prop::collection::vec(
+        prop_oneof![
+            prop::char::range('a', 'z'),
+            Just(' '),
+            prop::char::range('一', '龥'),
+            prop::char::range('😀', '🙏'),
+            Just('\u{200d}'),
+            Just('\u{301}'),
+            Just('𠀀'),
+        ],
+        0..40,
+    )
+}
+
+fn kind() -> BoxedStrategy<MessageEntityKind> {
+    prop_oneof![
+        Just(MessageEntityKind::Bold),
+        Just(MessageEntityKind::Italic),
+        Just(MessageEntityKind::Underline),
+        Just(MessageEntityKind::Strikethrough),
+        Just(MessageEntityKind::Spoiler),
+        Just(MessageEntityKind::Code),
+    ]
+    .boxed()
+}
+
+// Telegram only sends entities that are either nested or disjoint, and never split a character.
+fn message(
+    kind: BoxedStrategy<MessageEntityKind>,
+) -> impl Strategy<Value = (String, Vec<MessageEntity>)> {
+    text().prop_flat_map(move |chars| {
+        let len = chars.len();
+        let ranges = prop::collection::vec((0..=len, 0..=len, kind.clone()), 0..12);
+        (Just(chars), ranges).prop_map(|(chars, ranges)| {
+            let boundaries: Vec<usize> = chars
+                .iter()
+                .scan(0, |offset, chr| {
+                    let start = *offset;
+                    *offset += chr.len_utf16();
+                    Some(start)
+                })
+                .chain([chars.iter().map(|chr| chr.len_utf16()).sum()])
+                .collect();
+
+            let mut kept: Vec<(usize, usize)> = vec![];
+            let mut entities = vec![];
+            for (a, b, kind) in ranges {
+                let (start, end) = (a.min(b), a.max(b));
+                let laminar = kept.iter().all(|&(s, e)| {
+                    end <= s || e <= start || (s <= start && end <= e) || (start <= s && e <= end)
+                });
+                if start == end || !laminar {
+                    continue;
+                }
+                kept.push((start, end));
+                entities.push(MessageEntity::new(
+                    kind,
+                    boundaries[start],
+                    boundaries[end] - boundaries[start],
+                ));
+            }
+            (chars.into_iter().collect(), entities)
+        })
+    })
+}
+
+fn coverage(entities: &[MessageEntity]) -> HashMap<MessageEntityKind, HashSet<usize>> {
+    let mut coverage: HashMap<_, HashSet<_>> = HashMap::new();
+    for entity in entities {
+        coverage
+            .entry(entity.kind.clone())
+            .or_default()
+            .extend(entity.offset..entity.offset + entity.length);
+    }
+    coverage
+}
+
+fn is_grapheme_boundary(text: &str, at: usize) -> bool {
+    at == text.len() || text.grapheme_indices(true).any(|(i, _)| i == at)
+}
+
+fn sorted(mut entities: Vec<MessageEntity>) -> Vec<MessageEntity> {
+    entities.sort_by_key(|entity| (entity.offset, entity.length, format!("{:?}", entity.kind)));
+    entities
+}
+
+proptest! {
+    #[test]
+    fn build_keeps_text((text, entities) in message(kind())) {
+        let segments = Segments::build(&text, &entities);
+        prop_assert_eq!(segments.text(), text);
+    }
+
+    #[test]
+    fn build_then_entities_keeps_coverage((text, entities) in message(kind())) {
+        let segments = Segments::build(&text, &entities);
+        prop_assert_eq!(coverage(&segments.entities()), coverage(&entities));
+    }
+
+    #[test]
+    fn build_then_entities_round_trips(
+        (text, entities) in message(any::<u16>().prop_map(|id| MessageEntityKind::TextLink {
+            url: format!("https://example.com/{id}").parse().unwrap(),
+        }).boxed())
+    ) {
+        // Entities of distinct kinds are never merged, so they should come back as they were.
+        let mut seen = HashSet::new();
+        let entities: Vec<_> = entities.into_iter().filter(|entity| seen.insert(entity.kind.clone())).collect();
+
+        let segments = Segments::build(&text, &entities);
+        prop_assert_eq!(sorted(segments.entities()), sorted(entities));
+    }
+
+    #[test]
+    fn entities_are_within_text((text, entities) in message(kind())) {
+        let segments = Segments::build(&text, &entities);
+        let len = text.encode_utf16().count();
+        for entity in segments.entities() {
+            prop_assert!(entity.length > 0);
+            prop_assert!(entity.offset + entity.length <= len);
+        }
+    }
+
+    #[test]
+    fn drain_head_skips_chars((text, entities) in message(kind()), head in 0..50usize) {
+        let drained = Segments::build(&text, &entities).drain_head(CharOffset(head));
+        if head <= text.chars().count() {
+            let drained = drained.expect("text is long enough").text();
+            let dropped = &text[..text.len() - drained.len()];
+            prop_assert!(text.ends_with(&drained));
+            prop_assert!(dropped.chars().count() <= head);
+            prop_assert!(is_grapheme_boundary(&text, dropped.len()));
+        } else {
+            prop_assert!(drained.is_none());
+        }
+    }
+
+    #[test]
+    fn truncate_keeps_head((text, entities) in message(kind()), length in 0..50usize) {
+        let truncated = Segments::build(&text, &entities).truncate_to(CharOffset(length), "…");
+        if length < text.chars().count() {
+            let truncated = truncated.text();
+            let head = truncated.strip_suffix('…').expect("marker is added");
+            prop_assert!(text.starts_with(head));
+            prop_assert!(head.chars().count() <= length);
+            prop_assert!(is_grapheme_boundary(&text, head.len()));
+        } else {
+            prop_assert_eq!(truncated.text(), text);
+        }
+    }
+
+    #[test]
+    fn split_at_keeps_graphemes((text, entities) in message(kind()), at in 0..50usize) {
+        let segments = Segments::build(&text, &entities);
+        let graphemes: Vec<_> = text.graphemes(true).collect();
+        match segments.clone().split_at(GraphemeOffset(at)) {
+            Some((head, tail)) => {
+                prop_assert_eq!(head.text(), graphemes[..at].concat());
+                prop_assert_eq!(tail.text(), graphemes[at..].concat());
+                let mut joined = head;
+                joined.extend(tail.iter().cloned());
+                prop_assert_eq!(joined.entities(), segments.entities());
+            }
+            None => prop_assert!(at > graphemes.len()),
+        }
+    }
+
+    #[test]
+    fn split_at_never_cuts_graphemes((text, entities) in message(kind()), at in 0..80usize) {
+        let segments = Segments::build(&text, &entities);
+        if let Some((head, _)) = segments.split_at(Utf16Offset(at)) {
+            prop_assert!(head.utf16_len() <= at);
+            prop_assert!(is_grapheme_boundary(&text, head.text().len()));
+        }
+    }
+
+    #[test]
+    fn slice_is_between_splits(
+        (text, entities) in message(kind()),
+        a in 0..50usize,
+        b in 0..50usize,
+    ) {
+        let segments = Segments::build(&text, &entities);
+        let (start, end) = (CharOffset(a.min(b)), CharOffset(a.max(b)));
+        let sliced = segments.slice(start..end);
+        let head = segments.clone().split_at(end).map(|(head, _)| head.text());
+        let tail = segments.split_at(start).map(|(_, tail)| tail.text());
+        match (sliced, head, tail) {
+            (Some(sliced), Some(head), Some(tail)) => {
+                prop_assert!(head.ends_with(&sliced.text()));
+                prop_assert!(tail.starts_with(&sliced.text()));
+            }
+            (sliced, _, _) => prop_assert!(sliced.is_none()),
+        }
+    }
+
+    #[test]
+    fn normalize_keeps_text_and_entities((text, entities) in message(kind())) {
+        let segments = Segments::build(&text, &entities);
+        let normalized = segments.clone().normalize();
+        prop_assert_eq!(normalized.text(), segments.text());
+        prop_assert_eq!(normalized.entities(), segments.entities());
+        for (a, b) in normalized.iter().zip(normalized.iter().skip(1)) {
+            prop_assert_ne!(&a.kind, &b.kind);
+        }
+        prop_assert!(normalized.iter().all(|segment| !segment.text.is_empty()));
+    }
+
+    #[test]
+    fn entities_are_sorted((text, entities) in message(kind())) {
+        let entities = Segments::build(&text, &entities).entities();
+        for (a, b) in entities.iter().zip(entities.iter().skip(1)) {
+            prop_assert!(a.offset < b.offset || a.offset == b.offset && a.length >= b.length);
+        }
+    }
+}
+
+#[test]
+fn zwj_sequences_are_never_split() {
+    let segments = Segments::build("👩‍👩‍👧 family", &[]);
+    // The family is 8 UTF-16 code units and 5 chars, but one grapheme.
+    assert_eq!(
+        segments.clone().truncate_to(Utf16Offset(3), "…").text(),
+        "…"
+    );
+    assert_eq!(
+        segments.clone().drain_head(CharOffset(2)).unwrap().text(),
+        "👩‍👩‍👧 family"
+    );
+    assert_eq!(
+        segments.drain_head(GraphemeOffset(1)).unwrap().text(),
+        " family"
+    );
+}
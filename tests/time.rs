@@ -0,0 +1,28 @@
+use hithit_bot::time::parse_utc_offset;
+
+#[test]
+fn utc_offsets_are_parsed() {
+    assert_eq!(parse_utc_offset("UTC+8"), Some(8 * 3600));
+    assert_eq!(parse_utc_offset("+05:30"), Some(5 * 3600 + 30 * 60));
+    assert_eq!(parse_utc_offset("-0530"), Some(-(5 * 3600 + 30 * 60)));
+    assert_eq!(parse_utc_offset("-14"), Some(-14 * 3600));
+    assert_eq!(parse_utc_offset("gmt"), Some(0));
+}
+
+#[test]
+fn invalid_utc_offsets_are_rejected() {
+    for offset in [
+        "+15",
+        "--5",
+        "+-5",
+        "-+5",
+        "+",
+        "UTC+",
+        "+5:60",
+        "+08:00:00",
+        "東京",
+        "5 h",
+    ] {
+        assert_eq!(parse_utc_offset(offset), None, "{offset}");
+    }
+}